@@ -0,0 +1,152 @@
+mod y4m;
+mod images;
+mod ffmpeg;
+#[cfg(feature = "opencv")]
+mod cv;
+
+pub use ffmpeg::FfmpegOptions;
+
+use crate::{Codec, Error, Result};
+
+use std::path::Path;
+
+/// a video frame stored as rows of bgr pixels
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// a black image
+    pub fn new(width: u32, height: u32) -> Self {
+	Self {
+	    width,
+	    height,
+	    data: vec![0; (width * height * 3) as usize],
+	}
+    }
+
+    /// bgr color of the pixel at column `x` of row `y`
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+	let i = ((y * self.width + x) * 3) as usize;
+	[self.data[i], self.data[i + 1], self.data[i + 2]]
+    }
+}
+
+/// receives the frames of a video in order
+pub trait VideoSink: Send {
+    fn write(&mut self, image: &Image) -> Result<()>;
+
+    /// flushes everything written, the video is unusable before
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// gives back the frames of a video in order
+pub trait VideoSource: Send {
+    /// the next frame, `None` once the video ends
+    fn read(&mut self) -> Result<Option<Image>>;
+
+    /// the next read returns the frame at `index`, counting from 0
+    fn seek(&mut self, index: usize) -> Result<()>;
+}
+
+/// what reads and writes compressed videos, y4m files and images don't need one
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// only available with the opencv feature
+    OpenCv,
+    /// an `ffmpeg` process found in the PATH, the options only apply to encoding
+    Ffmpeg(FfmpegOptions),
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+	if cfg!(feature = "opencv") {
+	    Backend::OpenCv
+	} else {
+	    Backend::Ffmpeg(FfmpegOptions::default())
+	}
+    }
+}
+
+/// where the frames go, taken from the output path
+enum Format {
+    Y4m,
+    /// a directory holding one image per frame
+    Images,
+    /// any container the backend can write
+    Video,
+}
+
+impl Format {
+    /// what an output is written as, it may not exist yet so only its name counts
+    fn of_output(path: &str) -> Self {
+	let path = Path::new(path);
+
+	match path.extension().and_then(|e| e.to_str()) {
+	    Some(e) if e.eq_ignore_ascii_case("y4m") => Format::Y4m,
+	    Some(_) if !path.is_dir() => Format::Video,
+	    _ => Format::Images,
+	}
+    }
+
+    /// what an existing input is read as, any file other than a `.y4m` one is a video
+    /// whatever its extension, or lack of one
+    fn of_input(path: &str) -> Self {
+	let path = Path::new(path);
+
+	if path.is_dir() {
+	    Format::Images
+	} else if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("y4m")) {
+	    Format::Y4m
+	} else {
+	    Format::Video
+	}
+    }
+}
+
+#[cfg(not(feature = "opencv"))]
+fn missing_opencv(path: &str) -> Error {
+    Error::Parameter(format!("{path} needs the opencv feature, use the ffmpeg backend, a .y4m file or a directory of images instead"))
+}
+
+/// a `.y4m` output is written uncompressed, an output without extension is a directory of
+/// images and any other is compressed with `codec` by `backend`, the default codec when it
+/// is `None`. Asking for any codec on an uncompressed output is an error rather than ignored
+pub fn open_sink(output: &str, codec: Option<Codec>, backend: &Backend, fps: u32, width: u32, height: u32) -> Result<Box<dyn VideoSink>> {
+    let format = Format::of_output(output);
+    if let (Format::Y4m | Format::Images, Some(codec)) = (&format, codec) {
+	return Err(Error::Parameter(format!("{output} is written uncompressed, {codec:?} needs a mp4, mkv, webm or avi output")));
+    }
+    let codec = codec.unwrap_or_default();
+
+    match (format, backend) {
+	(Format::Y4m, _) => Ok(Box::new(y4m::Y4mSink::create(output, fps, width, height)?)),
+	(Format::Images, _) => Ok(Box::new(images::ImageSink::create(output, width, height)?)),
+	(Format::Video, Backend::Ffmpeg(options)) => Ok(Box::new(ffmpeg::FfmpegSink::create(output, codec, options, fps, width, height)?)),
+	#[cfg(feature = "opencv")]
+	(Format::Video, Backend::OpenCv) => Ok(Box::new(cv::OpenCvSink::create(output, codec, fps, width, height)?)),
+	#[cfg(not(feature = "opencv"))]
+	(Format::Video, Backend::OpenCv) => Err(missing_opencv(output)),
+    }
+}
+
+/// opens `input` as a directory of images, a `.y4m` file or, for any other file, a video
+/// read by `backend`
+pub fn open_source(input: &str, backend: &Backend) -> Result<Box<dyn VideoSource>> {
+    if !Path::new(input).exists() {
+	return Err(Error::Parameter(format!("{input} does not exist")));
+    }
+
+    match (Format::of_input(input), backend) {
+	(Format::Y4m, _) => Ok(Box::new(y4m::Y4mSource::open(input)?)),
+	(Format::Images, _) => Ok(Box::new(images::ImageSource::open(input)?)),
+	(Format::Video, Backend::Ffmpeg(_)) => Ok(Box::new(ffmpeg::FfmpegSource::open(input)?)),
+	#[cfg(feature = "opencv")]
+	(Format::Video, Backend::OpenCv) => Ok(Box::new(cv::OpenCvSource::open(input)?)),
+	#[cfg(not(feature = "opencv"))]
+	(Format::Video, Backend::OpenCv) => Err(missing_opencv(input)),
+    }
+}
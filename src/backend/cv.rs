@@ -0,0 +1,80 @@
+use super::{Image, VideoSink, VideoSource};
+use crate::{Codec, Error, Result};
+
+use opencv::prelude::{MatTraitConst, MatTraitConstManual, MatTraitManual};
+use opencv::videoio::{self, VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst, VideoWriter, VideoWriterTrait, VideoWriterTraitConst};
+use opencv::core::{Mat, Size, CV_8UC3};
+
+pub struct OpenCvSink {
+    video: VideoWriter,
+    /// reused between frames, opencv only writes `Mat`s
+    mat: Mat,
+}
+
+impl OpenCvSink {
+    pub fn create(output: &str, codec: Codec, fps: u32, width: u32, height: u32) -> Result<Self> {
+	let container = codec.check_container(output)?;
+
+	let [c1, c2, c3, c4] = codec.fourcc();
+	let fourcc = VideoWriter::fourcc(c1, c2, c3, c4)?;
+	let video = VideoWriter::new(output, fourcc, fps as f64, Size::new(width as i32, height as i32), true)?;
+
+	// opencv silently gives back a closed writer when its backend lacks the encoder
+	if !video.is_opened()? {
+	    return Err(Error::Parameter(format!("the video backend can not write {codec:?} in a {} file, try another codec", container.extension())));
+	}
+
+	let mat = unsafe { Mat::new_rows_cols(height as i32, width as i32, CV_8UC3)? };
+
+	Ok(Self { video, mat })
+    }
+}
+
+impl VideoSink for OpenCvSink {
+    fn write(&mut self, image: &Image) -> Result<()> {
+	self.mat.data_bytes_mut()?.copy_from_slice(&image.data);
+	self.video.write(&self.mat)?;
+	Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+	self.video.release()?;
+	Ok(())
+    }
+}
+
+pub struct OpenCvSource {
+    video: VideoCapture,
+    mat: Mat,
+}
+
+impl OpenCvSource {
+    pub fn open(input: &str) -> Result<Self> {
+	let video = VideoCapture::from_file(input, videoio::CAP_ANY)?;
+	if !video.is_opened()? {
+	    return Err(Error::Video(format!("opencv can not read {input}")));
+	}
+
+	Ok(Self { video, mat: Mat::default() })
+    }
+}
+
+impl VideoSource for OpenCvSource {
+    fn read(&mut self) -> Result<Option<Image>> {
+	if !self.video.read(&mut self.mat)? {
+	    return Ok(None);
+	}
+
+	// a decoded frame is always continuous
+	Ok(Some(Image {
+	    width: self.mat.cols() as u32,
+	    height: self.mat.rows() as u32,
+	    data: self.mat.data_bytes()?.to_vec(),
+	}))
+    }
+
+    fn seek(&mut self, index: usize) -> Result<()> {
+	self.video.set(videoio::CAP_PROP_POS_FRAMES, index as f64)?;
+	Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+use super::{Image, VideoSink, VideoSource};
+use crate::{Codec, Error, Result};
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// bytes kept from the end of what ffmpeg prints, for error messages
+const STDERR_TAIL: usize = 4096;
+
+/// encoder settings handed to ffmpeg, `None` keeps the default of the encoder
+#[derive(Debug, Clone, Default)]
+pub struct FfmpegOptions {
+    /// constant rate factor, lower keeps more details, unsupported by mjpeg and ffv1
+    pub crf: Option<u8>,
+    /// speed / compression trade-off, like `slow` for h264, `good` for vp9 or `6` for av1
+    pub preset: Option<String>,
+    /// defaults to bgr0 for ffv1 so that it stays lossless and yuv420p for the others
+    pub pix_fmt: Option<String>,
+    /// maximum number of frames between two keyframes
+    pub keyint: Option<u32>,
+}
+
+impl FfmpegOptions {
+    fn pix_fmt(&self, codec: Codec) -> &str {
+	match (&self.pix_fmt, codec) {
+	    (Some(p), _) => p,
+	    (None, c) if c.lossless() => "bgr0",
+	    (None, _) => "yuv420p",
+	}
+    }
+
+    /// arguments selecting and tuning the encoder of `codec`
+    fn args(&self, codec: Codec) -> Result<Vec<String>> {
+	let mut args = vec!["-c:v".to_string(), encoder(codec).to_string()];
+
+	if let Some(crf) = self.crf {
+	    match codec {
+		Codec::H264 | Codec::Av1 => args.extend(["-crf".to_string(), crf.to_string()]),
+		// vp9 only follows the crf without a target bitrate
+		Codec::Vp9 => args.extend(["-crf".to_string(), crf.to_string(), "-b:v".to_string(), "0".to_string()]),
+		Codec::Mjpeg | Codec::Ffv1 => return Err(Error::Parameter(format!("{codec:?} has no crf setting"))),
+	    }
+	}
+
+	if let Some(preset) = &self.preset {
+	    match codec {
+		Codec::H264 | Codec::Av1 => args.extend(["-preset".to_string(), preset.clone()]),
+		Codec::Vp9 => args.extend(["-deadline".to_string(), preset.clone()]),
+		Codec::Mjpeg | Codec::Ffv1 => return Err(Error::Parameter(format!("{codec:?} has no preset setting"))),
+	    }
+	}
+
+	if let Some(keyint) = self.keyint {
+	    args.extend(["-g".to_string(), keyint.to_string()]);
+	}
+
+	args.extend(["-pix_fmt".to_string(), self.pix_fmt(codec).to_string()]);
+
+	Ok(args)
+    }
+
+    /// one `key=value` line per setting
+    fn sidecar(&self, codec: Codec, fps: u32, width: u32, height: u32) -> String {
+	let or_default = |v: Option<String>| v.unwrap_or_else(|| "default".to_string());
+
+	format!(
+	    "backend=ffmpeg\nencoder={}\ncrf={}\npreset={}\npix_fmt={}\nkeyint={}\nfps={fps}\nresolution={width}x{height}\n",
+	    encoder(codec),
+	    or_default(self.crf.map(|c| c.to_string())),
+	    or_default(self.preset.clone()),
+	    self.pix_fmt(codec),
+	    or_default(self.keyint.map(|k| k.to_string())),
+	)
+    }
+}
+
+/// name of the ffmpeg encoder used for `codec`
+fn encoder(codec: Codec) -> &'static str {
+    match codec {
+	Codec::H264 => "libx264",
+	Codec::Vp9 => "libvpx-vp9",
+	Codec::Av1 => "libsvtav1",
+	Codec::Mjpeg => "mjpeg",
+	Codec::Ffv1 => "ffv1",
+    }
+}
+
+fn spawn(program: &str, args: &[String], stdin: Stdio, stdout: Stdio) -> Result<Child> {
+    match Command::new(program).args(args).stdin(stdin).stdout(stdout).stderr(Stdio::piped()).spawn() {
+	Ok(child) => Ok(child),
+	Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::Parameter(format!("{program} is not installed or not in the PATH"))),
+	Err(e) => Err(e.into()),
+    }
+}
+
+/// reads what `child` prints on its own thread, a damaged video can make ffmpeg print enough
+/// errors to fill the pipe and block it while its output is read. Only the end is kept
+fn drain_stderr(child: &mut Child) -> Option<JoinHandle<String>> {
+    let mut stderr = child.stderr.take()?;
+
+    Some(thread::spawn(move || {
+	let mut tail = Vec::new();
+	let mut buf = [0; 4096];
+
+	while let Ok(n @ 1..) = stderr.read(&mut buf) {
+	    tail.extend_from_slice(&buf[..n]);
+	    if tail.len() > STDERR_TAIL {
+		tail.drain(..tail.len() - STDERR_TAIL);
+	    }
+	}
+
+	String::from_utf8_lossy(&tail).into_owned()
+    }))
+}
+
+/// waits for `child` and turns a failure into an error holding what it printed
+fn wait(mut child: Child, stderr: Option<JoinHandle<String>>) -> Result<()> {
+    let status = child.wait()?;
+    let stderr = stderr.and_then(|s| s.join().ok()).unwrap_or_default();
+
+    if !status.success() {
+	return Err(Error::Video(format!("ffmpeg failed: {}", stderr.trim())));
+    }
+
+    Ok(())
+}
+
+/// errors if the ffmpeg found in the PATH was built without the encoder of `codec`
+fn check_encoder(codec: Codec) -> Result<()> {
+    let child = spawn("ffmpeg", &["-hide_banner".to_string(), "-encoders".to_string()], Stdio::null(), Stdio::piped())?;
+    let output = child.wait_with_output()?;
+    let encoders = String::from_utf8_lossy(&output.stdout);
+
+    if !encoders.lines().any(|l| l.split_whitespace().nth(1) == Some(encoder(codec))) {
+	return Err(Error::Parameter(format!("ffmpeg was built without {}, the {codec:?} encoder", encoder(codec))));
+    }
+
+    Ok(())
+}
+
+/// pipes raw bgr frames to an ffmpeg process, the settings are written next to the video
+/// in `<output>.ytstorage`
+pub struct FfmpegSink {
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl FfmpegSink {
+    pub fn create(output: &str, codec: Codec, options: &FfmpegOptions, fps: u32, width: u32, height: u32) -> Result<Self> {
+	codec.check_container(output)?;
+	check_encoder(codec)?;
+
+	let mut args: Vec<String> = [
+	    "-hide_banner", "-loglevel", "error", "-y",
+	    "-f", "rawvideo", "-pix_fmt", "bgr24",
+	].iter().map(|a| a.to_string()).collect();
+	args.extend(["-s".to_string(), format!("{width}x{height}"), "-r".to_string(), fps.to_string(), "-i".to_string(), "-".to_string()]);
+	args.extend(options.args(codec)?);
+	args.push(output.to_string());
+
+	fs::write(format!("{output}.ytstorage"), options.sidecar(codec, fps, width, height))?;
+
+	let mut child = spawn("ffmpeg", &args, Stdio::piped(), Stdio::null())?;
+	let stdin = child.stdin.take();
+	let stderr = drain_stderr(&mut child);
+
+	Ok(Self { child: Some(child), stdin, stderr })
+    }
+
+    /// ffmpeg stopped reading, its own error says more than the broken pipe
+    fn failure(&mut self, e: io::Error) -> Error {
+	self.stdin = None;
+	match self.child.take().map(|c| wait(c, self.stderr.take())) {
+	    Some(Err(failure)) => failure,
+	    _ => e.into(),
+	}
+    }
+}
+
+impl VideoSink for FfmpegSink {
+    fn write(&mut self, image: &Image) -> Result<()> {
+	let result = match &mut self.stdin {
+	    Some(stdin) => stdin.write_all(&image.data),
+	    None => return Err(Error::Video("ffmpeg is not running anymore".to_string())),
+	};
+
+	result.map_err(|e| self.failure(e))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+	// closing stdin ends the video
+	self.stdin = None;
+	match self.child.take() {
+	    Some(child) => wait(child, self.stderr.take()),
+	    None => Ok(()),
+	}
+    }
+}
+
+impl Drop for FfmpegSink {
+    /// the video is left unfinished when it was not finished, ffmpeg must not outlive it
+    fn drop(&mut self) {
+	self.stdin = None;
+	if let Some(mut child) = self.child.take() {
+	    let _ = child.kill();
+	    let _ = child.wait();
+	}
+    }
+}
+
+/// reads raw bgr frames from an ffmpeg process, which is restarted to seek
+pub struct FfmpegSource {
+    input: String,
+    width: u32,
+    height: u32,
+    /// frames per second as a fraction, to turn frame indexes into timestamps
+    rate: (u64, u64),
+    child: Child,
+    stdout: ChildStdout,
+    stderr: Option<JoinHandle<String>>,
+    /// index of the next frame ffmpeg gives back
+    position: usize,
+}
+
+impl FfmpegSource {
+    pub fn open(input: &str) -> Result<Self> {
+	let args: Vec<String> = [
+	    "-v", "error", "-select_streams", "v:0",
+	    "-show_entries", "stream=width,height,r_frame_rate", "-of", "csv=p=0",
+	    input,
+	].iter().map(|a| a.to_string()).collect();
+
+	let output = spawn("ffprobe", &args, Stdio::null(), Stdio::piped())?.wait_with_output()?;
+	let probe = String::from_utf8_lossy(&output.stdout);
+
+	// width,height,num/den
+	let fields: Vec<&str> = probe.trim().split([',', '/']).collect();
+	let number = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok()).filter(|n| *n > 0);
+
+	let (width, height, rate) = match (number(0), number(1), number(2), number(3)) {
+	    (Some(w), Some(h), Some(num), Some(den)) => (w as u32, h as u32, (num, den)),
+	    _ => return Err(Error::Video(format!("ffprobe can not read the video stream of {input}: {}", probe.trim()))),
+	};
+
+	let (mut child, stdout) = Self::decode_from(input, rate, 0)?;
+	let stderr = drain_stderr(&mut child);
+
+	Ok(Self { input: input.to_string(), width, height, rate, child, stdout, stderr, position: 0 })
+    }
+
+    /// starts an ffmpeg process giving back the frames from `index` on
+    fn decode_from(input: &str, (num, den): (u64, u64), index: usize) -> Result<(Child, ChildStdout)> {
+	let mut args: Vec<String> = vec!["-v".to_string(), "error".to_string()];
+
+	if index > 0 {
+	    // half a frame early so that rounding never skips the wanted frame,
+	    // seeking before the input is frame accurate when decoding
+	    let seconds = (index as f64 - 0.5) * den as f64 / num as f64;
+	    args.extend(["-ss".to_string(), format!("{seconds:.6}")]);
+	}
+
+	args.extend(["-i".to_string(), input.to_string()]);
+	// every decoded frame as it is, without duplicating or dropping any
+	args.extend(["-fps_mode", "passthrough", "-f", "rawvideo", "-pix_fmt", "bgr24", "-"].iter().map(|a| a.to_string()));
+
+	let mut child = spawn("ffmpeg", &args, Stdio::null(), Stdio::piped())?;
+	match child.stdout.take() {
+	    Some(stdout) => Ok((child, stdout)),
+	    None => Err(Error::Video("the output of ffmpeg can not be read".to_string())),
+	}
+    }
+}
+
+impl VideoSource for FfmpegSource {
+    fn read(&mut self) -> Result<Option<Image>> {
+	let mut image = Image::new(self.width, self.height);
+
+	// a partial frame means ffmpeg stopped, the video ended or it failed
+	let mut filled = 0;
+	while filled < image.data.len() {
+	    match self.stdout.read(&mut image.data[filled..])? {
+		0 => break,
+		n => filled += n,
+	    }
+	}
+
+	if filled < image.data.len() {
+	    let status = self.child.wait()?;
+	    let stderr = self.stderr.take().and_then(|s| s.join().ok()).unwrap_or_default();
+
+	    if !status.success() {
+		return Err(Error::Video(format!("ffmpeg failed: {}", stderr.trim())));
+	    }
+	    return Ok(None);
+	}
+
+	self.position += 1;
+	Ok(Some(image))
+    }
+
+    fn seek(&mut self, index: usize) -> Result<()> {
+	if index == self.position {
+	    return Ok(());
+	}
+
+	let _ = self.child.kill();
+	let _ = self.child.wait();
+
+	(self.child, self.stdout) = Self::decode_from(&self.input, self.rate, index)?;
+	self.stderr = drain_stderr(&mut self.child);
+	self.position = index;
+
+	Ok(())
+    }
+}
+
+impl Drop for FfmpegSource {
+    fn drop(&mut self) {
+	let _ = self.child.kill();
+	let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,191 @@
+use super::{Image, VideoSink, VideoSource};
+use crate::{Error, Result};
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// writes every frame as a numbered lossless png in a directory
+pub struct ImageSink {
+    dir: PathBuf,
+    index: usize,
+    /// one frame of rgb pixels, reused between frames
+    rgb: Vec<u8>,
+}
+
+impl ImageSink {
+    pub fn create(output: &str, width: u32, height: u32) -> Result<Self> {
+	fs::create_dir_all(output)?;
+
+	Ok(Self {
+	    dir: PathBuf::from(output),
+	    index: 0,
+	    rgb: Vec::with_capacity((width * height * 3) as usize),
+	})
+    }
+}
+
+impl VideoSink for ImageSink {
+    fn write(&mut self, image: &Image) -> Result<()> {
+	self.rgb.clear();
+	for bgr in image.data.chunks_exact(3) {
+	    self.rgb.extend_from_slice(&[bgr[2], bgr[1], bgr[0]]);
+	}
+
+	let path = self.dir.join(format!("{:08}.png", self.index));
+	let mut encoder = png::Encoder::new(BufWriter::new(File::create(&path)?), image.width, image.height);
+	encoder.set_color(png::ColorType::Rgb);
+	encoder.set_depth(png::BitDepth::Eight);
+
+	encoder.write_header()
+	    .and_then(|mut writer| writer.write_image_data(&self.rgb))
+	    .map_err(|e| Error::Video(format!("can not write {}: {e}", path.display())))?;
+
+	self.index += 1;
+	Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+	Ok(())
+    }
+}
+
+/// reads the png and ppm images of a directory in the order of their names,
+/// so their numbers must be padded with zeros
+pub struct ImageSource {
+    paths: Vec<PathBuf>,
+    index: usize,
+    width: u32,
+    height: u32,
+}
+
+impl ImageSource {
+    pub fn open(input: &str) -> Result<Self> {
+	let mut paths = Vec::new();
+	for entry in fs::read_dir(input)? {
+	    let path = entry?.path();
+	    if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("png") || e.eq_ignore_ascii_case("ppm")) {
+		paths.push(path);
+	    }
+	}
+	paths.sort();
+
+	let (width, height) = match paths.first().map(|p| (p, read_image(p))) {
+	    Some((_, Ok(Some(image)))) => (image.width, image.height),
+	    Some((_, Err(e))) => return Err(e),
+	    Some((p, Ok(None))) => return Err(Error::Video(format!("{} is not an 8 bits png or binary ppm", p.display()))),
+	    None => return Err(Error::Video(format!("{input} holds no image"))),
+	};
+
+	Ok(Self { paths, index: 0, width, height })
+    }
+}
+
+impl VideoSource for ImageSource {
+    fn read(&mut self) -> Result<Option<Image>> {
+	let path = match self.paths.get(self.index) {
+	    Some(p) => p,
+	    None => return Ok(None),
+	};
+	self.index += 1;
+
+	match read_image(path)? {
+	    Some(image) if (image.width, image.height) == (self.width, self.height) => Ok(Some(image)),
+	    _ => Err(Error::Video(format!("{} is not a {}x{} image like the first one", path.display(), self.width, self.height))),
+	}
+    }
+
+    fn seek(&mut self, index: usize) -> Result<()> {
+	self.index = index;
+	Ok(())
+    }
+}
+
+/// `None` if the file is neither a png nor a binary ppm with 8 bits channels
+fn read_image(path: &Path) -> Result<Option<Image>> {
+    match path.extension().and_then(|e| e.to_str()) {
+	Some(e) if e.eq_ignore_ascii_case("png") => read_png(path),
+	_ => read_ppm(path),
+    }
+}
+
+fn read_png(path: &Path) -> Result<Option<Image>> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // palettes and small depths become 8 bits channels
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+    let mut reader = match decoder.read_info() {
+	Ok(r) => r,
+	Err(png::DecodingError::IoError(e)) => return Err(e.into()),
+	Err(_) => return Ok(None),
+    };
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = match reader.next_frame(&mut buffer) {
+	Ok(i) => i,
+	Err(png::DecodingError::IoError(e)) => return Err(e.into()),
+	Err(_) => return Ok(None),
+    };
+
+    let channels = match info.color_type {
+	png::ColorType::Grayscale => 1,
+	png::ColorType::GrayscaleAlpha => 2,
+	png::ColorType::Rgb => 3,
+	png::ColorType::Rgba => 4,
+	png::ColorType::Indexed => return Ok(None),
+    };
+
+    let mut image = Image::new(info.width, info.height);
+    for (y, line) in buffer.chunks_exact(info.line_size).take(info.height as usize).enumerate() {
+	let start = y * info.width as usize * 3;
+	let row = &mut image.data[start..start + info.width as usize * 3];
+
+	for (bgr, pixel) in row.chunks_exact_mut(3).zip(line.chunks_exact(channels)) {
+	    // the alpha channel is ignored
+	    let rgb = if channels < 3 { [pixel[0]; 3] } else { [pixel[0], pixel[1], pixel[2]] };
+	    bgr.copy_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+	}
+    }
+
+    Ok(Some(image))
+}
+
+fn read_ppm(path: &Path) -> Result<Option<Image>> {
+    let bytes = fs::read(path)?;
+
+    // the header is 4 fields separated by whitespace, comments can sit in between
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 && pos < bytes.len() {
+	match bytes[pos] {
+	    b'#' => while pos < bytes.len() && bytes[pos] != b'\n' { pos += 1 },
+	    b if b.is_ascii_whitespace() => pos += 1,
+	    _ => {
+		let start = pos;
+		while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() { pos += 1 }
+		fields.push(String::from_utf8_lossy(&bytes[start..pos]).into_owned());
+	    },
+	}
+    }
+    // a single whitespace separates the header from the pixels
+    pos += 1;
+
+    let number = |i: usize| fields.get(i).and_then(|f| f.parse::<u32>().ok());
+    let (width, height) = match (fields.first().map(String::as_str), number(1), number(2), number(3)) {
+	(Some("P6"), Some(w), Some(h), Some(255)) => (w, h),
+	_ => return Ok(None),
+    };
+
+    let len = (width * height * 3) as usize;
+    let rgb = match bytes.get(pos..pos + len) {
+	Some(rgb) => rgb,
+	None => return Ok(None),
+    };
+
+    let mut image = Image::new(width, height);
+    for (bgr, rgb) in image.data.chunks_exact_mut(3).zip(rgb.chunks_exact(3)) {
+	bgr.copy_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+    }
+
+    Ok(Some(image))
+}
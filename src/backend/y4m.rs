@@ -0,0 +1,189 @@
+use super::{Image, VideoSink, VideoSource};
+use crate::{Error, Result, bgr_to_yuv, yuv_to_bgr, clamp};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+/// chroma planes of a y4m file, relative to the luma plane
+#[derive(Clone, Copy)]
+enum Chroma {
+    /// full resolution
+    C444,
+    /// half width and half height
+    C420,
+    /// no chroma planes
+    Mono,
+}
+
+impl Chroma {
+    fn plane_len(&self, width: u32, height: u32) -> usize {
+	match self {
+	    Chroma::C444 => (width * height) as usize,
+	    Chroma::C420 => (width.div_ceil(2) * height.div_ceil(2)) as usize,
+	    Chroma::Mono => 0,
+	}
+    }
+}
+
+/// writes frames as uncompressed 4:4:4 full range yuv
+pub struct Y4mSink {
+    file: BufWriter<File>,
+    /// one frame of yuv planes, reused between frames
+    planes: Vec<u8>,
+}
+
+impl Y4mSink {
+    pub fn create(output: &str, fps: u32, width: u32, height: u32) -> Result<Self> {
+	let mut file = BufWriter::new(File::create(output)?);
+	writeln!(file, "YUV4MPEG2 W{width} H{height} F{fps}:1 Ip A1:1 C444 XCOLORRANGE=FULL")?;
+
+	Ok(Self {
+	    file,
+	    planes: vec![0; (width * height * 3) as usize],
+	})
+    }
+}
+
+impl VideoSink for Y4mSink {
+    fn write(&mut self, image: &Image) -> Result<()> {
+	let len = (image.width * image.height) as usize;
+	let (y, uv) = self.planes.split_at_mut(len);
+	let (u, v) = uv.split_at_mut(len);
+
+	for (i, bgr) in image.data.chunks_exact(3).enumerate() {
+	    [y[i], u[i], v[i]] = bgr_to_yuv(bgr);
+	}
+
+	self.file.write_all(b"FRAME\n")?;
+	self.file.write_all(&self.planes)?;
+	Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+	self.file.flush()?;
+	Ok(())
+    }
+}
+
+/// reads 4:4:4, 4:2:0 and mono files, limited range unless the header says otherwise
+pub struct Y4mSource {
+    file: BufReader<File>,
+    width: u32,
+    height: u32,
+    chroma: Chroma,
+    full_range: bool,
+    /// offset of the first frame
+    start: u64,
+    planes: Vec<u8>,
+}
+
+impl Y4mSource {
+    pub fn open(input: &str) -> Result<Self> {
+	let mut file = BufReader::new(File::open(input)?);
+
+	let mut header = String::new();
+	file.read_line(&mut header)?;
+
+	let mut params = header.split_ascii_whitespace();
+	if params.next() != Some("YUV4MPEG2") {
+	    return Err(Error::Video(format!("{input} is not a y4m file")));
+	}
+
+	let (mut width, mut height) = (0, 0);
+	let mut chroma = Chroma::C420;
+	let mut full_range = false;
+
+	for param in params.filter(|p| p.is_char_boundary(1)) {
+	    let (key, value) = param.split_at(1);
+	    match key {
+		"W" => width = value.parse().unwrap_or(0),
+		"H" => height = value.parse().unwrap_or(0),
+		"C" => chroma = match value {
+		    v if v.starts_with("444") => Chroma::C444,
+		    v if v.starts_with("420") => Chroma::C420,
+		    "mono" => Chroma::Mono,
+		    v => return Err(Error::Video(format!("{input} uses the unsupported {v} chroma subsampling"))),
+		},
+		"X" if value == "COLORRANGE=FULL" => full_range = true,
+		_ => {},
+	    }
+	}
+
+	if width == 0 || height == 0 {
+	    return Err(Error::Video(format!("{input} has no valid resolution in its header")));
+	}
+
+	let len = (width * height) as usize + 2 * chroma.plane_len(width, height);
+
+	Ok(Self {
+	    file,
+	    width,
+	    height,
+	    chroma,
+	    full_range,
+	    start: header.len() as u64,
+	    planes: vec![0; len],
+	})
+    }
+}
+
+impl VideoSource for Y4mSource {
+    fn read(&mut self) -> Result<Option<Image>> {
+	let mut marker = Vec::new();
+	if self.file.read_until(b'\n', &mut marker)? == 0 {
+	    return Ok(None);
+	}
+
+	if !marker.starts_with(b"FRAME") {
+	    return Err(Error::Video("a y4m frame does not start with FRAME".to_string()));
+	}
+
+	self.file.read_exact(&mut self.planes)?;
+
+	let (width, height) = (self.width, self.height);
+	let luma_len = (width * height) as usize;
+	let chroma_len = self.chroma.plane_len(width, height);
+
+	let (y, uv) = self.planes.split_at(luma_len);
+	let (u, v) = uv.split_at(chroma_len);
+
+	let mut image = Image::new(width, height);
+	for (i, bgr) in image.data.chunks_exact_mut(3).enumerate() {
+	    let (row, column) = (i as u32 / width, i as u32 % width);
+
+	    let c = match self.chroma {
+		Chroma::C444 => Some(i),
+		Chroma::C420 => Some(((row / 2) * width.div_ceil(2) + column / 2) as usize),
+		Chroma::Mono => None,
+	    };
+
+	    let mut yuv = match c {
+		Some(c) => [y[i], u[c], v[c]],
+		None => [y[i], 128, 128],
+	    };
+
+	    if !self.full_range {
+		yuv = expand_range(yuv);
+	    }
+
+	    bgr.copy_from_slice(&yuv_to_bgr(yuv));
+	}
+
+	Ok(Some(image))
+    }
+
+    /// assumes every frame header is a bare `FRAME`, which is how y4m files are written in practice
+    fn seek(&mut self, index: usize) -> Result<()> {
+	let frame_len = (b"FRAME\n".len() + self.planes.len()) as u64;
+	self.file.seek(SeekFrom::Start(self.start + index as u64 * frame_len))?;
+	Ok(())
+    }
+}
+
+/// maps limited range (16-235 luma, 16-240 chroma) to full range
+fn expand_range([y, u, v]: [u8; 3]) -> [u8; 3] {
+    let y = (y as f32 - 16.0) * 255.0 / 219.0;
+    let chroma = |c: u8| (c as f32 - 128.0) * 255.0 / 224.0 + 128.0;
+
+    [clamp(y), clamp(chroma(u)), clamp(chroma(v))]
+}
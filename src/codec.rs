@@ -0,0 +1,89 @@
+use crate::{Error, Result};
+
+use std::path::Path;
+
+/// compression used for the frames of the video
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// what youtube expects, lossy
+    #[default]
+    H264,
+    Vp9,
+    Av1,
+    /// every frame compressed on its own, large but simple
+    Mjpeg,
+    /// lossless, meant for local archives and only stored in mkv
+    Ffv1,
+}
+
+/// file format holding the video, taken from the extension of the output
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Webm,
+    Avi,
+}
+
+impl Codec {
+    #[cfg(feature = "opencv")]
+    pub(crate) fn fourcc(&self) -> [char; 4] {
+	match self {
+	    Codec::H264 => ['a', 'v', 'c', '1'],
+	    Codec::Vp9 => ['V', 'P', '9', '0'],
+	    Codec::Av1 => ['a', 'v', '0', '1'],
+	    Codec::Mjpeg => ['M', 'J', 'P', 'G'],
+	    Codec::Ffv1 => ['F', 'F', 'V', '1'],
+	}
+    }
+
+    pub fn lossless(&self) -> bool {
+	matches!(self, Codec::Ffv1)
+    }
+
+    /// containers the codec can be stored in
+    pub fn containers(&self) -> &'static [Container] {
+	match self {
+	    Codec::H264 => &[Container::Mp4, Container::Mkv, Container::Avi],
+	    Codec::Vp9 => &[Container::Webm, Container::Mkv, Container::Mp4],
+	    Codec::Av1 => &[Container::Mp4, Container::Mkv, Container::Webm],
+	    Codec::Mjpeg => &[Container::Avi, Container::Mkv],
+	    Codec::Ffv1 => &[Container::Mkv],
+	}
+    }
+
+    /// errors if the container of `output` cannot hold this codec
+    pub fn check_container(&self, output: &str) -> Result<Container> {
+	let container = Container::from_path(output)?;
+
+	if !self.containers().contains(&container) {
+	    let supported = self.containers().iter().map(|c| c.extension()).collect::<Vec<_>>().join(", ");
+	    return Err(Error::Parameter(format!("{self:?} can not be stored in a {} file, use one of: {supported}", container.extension())));
+	}
+
+	Ok(container)
+    }
+}
+
+impl Container {
+    pub fn from_path(path: &str) -> Result<Self> {
+	let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or_default();
+
+	match extension.to_ascii_lowercase().as_str() {
+	    "mp4" => Ok(Container::Mp4),
+	    "mkv" => Ok(Container::Mkv),
+	    "webm" => Ok(Container::Webm),
+	    "avi" => Ok(Container::Avi),
+	    _ => Err(Error::Parameter(format!("{path} needs a mp4, mkv, webm or avi extension to choose the container"))),
+	}
+    }
+
+    pub fn extension(&self) -> &'static str {
+	match self {
+	    Container::Mp4 => "mp4",
+	    Container::Mkv => "mkv",
+	    Container::Webm => "webm",
+	    Container::Avi => "avi",
+	}
+    }
+}
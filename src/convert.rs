@@ -1,97 +1,199 @@
-use crate::error;
-
-use num_traits::Num;
-use itertools::Itertools;
-use std::process;
-
-pub fn dec_to_bin<T: Into<u32>>(dec: T, bits: usize) -> Vec<bool> {
-    let decimal: u32 = dec.into();
-    
-    let mut binstr = format!("{:b}", decimal);
-    while binstr.len() < bits {
-	binstr = format!("0{}", binstr);
+/// bits packed in bytes, most significant bit first
+#[derive(Debug, Default, Clone)]
+pub struct BitBuffer {
+    bytes: Vec<u8>,
+    len: usize,
+    /// confidence of every bit, only kept when asked for
+    confidence: Option<Vec<u8>>,
+}
+
+impl BitBuffer {
+    pub fn with_capacity(bits: usize) -> Self {
+	Self {
+	    bytes: Vec::with_capacity(bits.div_ceil(8)),
+	    len: 0,
+	    confidence: None,
+	}
     }
 
-    binstr.chars().map(|c| {
-	match c {
-	    '0' => false,
-	    '1' => true,
-	    _ => process::exit(1)
+    /// a buffer keeping the confidence of every bit, when `soft` is set
+    pub fn soft(bits: usize, soft: bool) -> Self {
+	Self {
+	    confidence: soft.then(|| Vec::with_capacity(bits)),
+	    ..Self::with_capacity(bits)
 	}
-    }).collect()
-}
+    }
+
+    /// whether the confidence of the bits is kept
+    pub fn is_soft(&self) -> bool {
+	self.confidence.is_some()
+    }
+
+    /// pushes `bit`, decided with `confidence`
+    pub fn push_soft(&mut self, bit: bool, confidence: u8) {
+	if let Some(c) = &mut self.confidence {
+	    c.push(confidence);
+	}
+
+	self.push(bit);
+    }
 
-pub fn bin_to_dec<T>(bin: &[bool]) -> T
-where
-    T: Num,
-{
-    let mut binstr = String::new();
-    for b in bin {
-	match b {
-	    false => binstr = format!("{}0", binstr),
-	    true => binstr = format!("{}1", binstr)
+    pub fn push(&mut self, bit: bool) {
+	if self.len.is_multiple_of(8) {
+	    self.bytes.push(0);
 	}
+
+	if bit {
+	    *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+	}
+
+	self.len += 1;
     }
 
-    match T::from_str_radix(&binstr, 2) {
-	Ok(d) => d,
-	Err(_) => error("failed to convert bin to dec"),
+    pub fn into_bytes(self) -> Vec<u8> {
+	self.bytes
+    }
+
+    /// the bytes and the confidence of every bit, which is empty unless it was kept
+    pub fn into_parts(self) -> (Vec<u8>, Vec<u8>) {
+	(self.bytes, self.confidence.unwrap_or_default())
     }
 }
 
-pub fn byte_to_bits(byte: &u8) -> Vec<bool> {
-    let mut cbyte = format!("{:b}", byte);
-    while cbyte.len() < 8 {
-	cbyte = format!("0{}", cbyte);
+/// bit at `index` in `bytes`, most significant bit first, out of bounds bits are 0
+pub fn get_bit(bytes: &[u8], index: usize) -> bool {
+    match bytes.get(index / 8) {
+	Some(b) => b & (0x80 >> (index % 8)) != 0,
+	None => false,
     }
+}
 
-    return cbyte.chars().map(|c| {
-	match c {
-	    '0' => false,
-	    '1' => true,
-	    _ => process::exit(1)
-	}
-    }).collect();
+/// string stored in a zero padded field
+pub fn bytes_to_str(bytes: &[u8]) -> String {
+    let bytes: Vec<u8> = bytes.iter().copied().filter(|b| *b != 0).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
 }
 
-pub fn bits_to_byte(bits: &[bool]) -> u8 {
-    assert!(bits.len() == 8);
-    let mut tmp_str = String::new();
-    for b in bits.iter() {
-	match b {
-	    false => tmp_str = format!("{}0", tmp_str),
-	    true => tmp_str = format!("{}1", tmp_str),
-	}
+/// gray code of `n`, consecutive numbers only differ by one bit
+pub fn to_gray(n: u32) -> u32 {
+    n ^ (n >> 1)
+}
+
+/// number whose gray code is `g`
+pub fn from_gray(mut g: u32) -> u32 {
+    let mut n = g;
+    while g > 0 {
+	g >>= 1;
+	n ^= g;
     }
+    n
+}
 
-    u8::from_str_radix(&tmp_str, 2).unwrap()
+/// full range bt.601, as used by jpeg
+pub fn bgr_to_yuv(bgr: &[u8]) -> [u8; 3] {
+    let (b, g, r) = (bgr[0] as f32, bgr[1] as f32, bgr[2] as f32);
+
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let v = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+
+    [clamp(y), clamp(u), clamp(v)]
+}
+
+pub fn yuv_to_bgr([y, u, v]: [u8; 3]) -> [u8; 3] {
+    let (y, u, v) = (y as f32, u as f32 - 128.0, v as f32 - 128.0);
+
+    let r = y + 1.402 * v;
+    let g = y - 0.344136 * u - 0.714136 * v;
+    let b = y + 1.772 * u;
+
+    [clamp(b), clamp(g), clamp(r)]
 }
 
-pub fn pages_to_bytes(bits: &[bool]) -> Vec<u8> {
-    let mut out = Vec::new();
-    for chunk in &bits.iter().chunks(8) {
-	let mut bin = String::new();
-	for b in chunk {
-	    match b {
-		false => bin = format!("{}0", bin),
-		true => bin = format!("{}1", bin),
-	    }
+/// rounds to the nearest 8 bit level
+pub fn clamp(c: f32) -> u8 {
+    c.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_are_packed_most_significant_first() {
+	let bits = [true, false, true, true, false, false, false, true, true];
+	let mut buffer = BitBuffer::with_capacity(bits.len());
+	for bit in bits {
+	    buffer.push(bit);
 	}
-	out.push(u8::from_str_radix(&bin, 2).unwrap());
+
+	let bytes = buffer.into_bytes();
+	assert_eq!(bytes, [0b1011_0001, 0b1000_0000]);
+
+	for (i, bit) in bits.iter().enumerate() {
+	    assert_eq!(get_bit(&bytes, i), *bit);
+	}
+	assert!(!get_bit(&bytes, 64));
     }
 
-    out
-}
+    #[test]
+    fn confidence_is_only_kept_when_soft() {
+	let mut hard = BitBuffer::soft(2, false);
+	hard.push_soft(true, 10);
+	assert!(!hard.is_soft());
+	assert!(hard.into_parts().1.is_empty());
 
-pub fn bin_to_str(bits: &[bool]) -> String {
-    let mut out = String::new();
-    for c in &bits.iter().chunks(8) {
-	let byte = c.copied().collect::<Vec<bool>>();
+	let mut soft = BitBuffer::soft(2, true);
+	soft.push_soft(true, 10);
+	soft.push_soft(false, 20);
+	assert_eq!(soft.into_parts(), (vec![0b1000_0000], vec![10, 20]));
+    }
 
-	if byte != vec![false; 8] {
-	    out = format!("{}{}", out, bits_to_byte(&byte) as char);
+    #[test]
+    fn gray_code_round_trips_and_steps_by_one_bit() {
+	for n in 0..4096 {
+	    assert_eq!(from_gray(to_gray(n)), n);
+	    assert_eq!((to_gray(n) ^ to_gray(n + 1)).count_ones(), 1);
 	}
     }
 
-    out
+    /// prints how fast bits are unpacked and packed, against the string formatting used
+    /// before BitBuffer: `cargo test --release -- --ignored --nocapture packing_throughput`
+    #[test]
+    #[ignore]
+    fn packing_throughput() {
+	use std::hint::black_box;
+	use std::time::Instant;
+
+	let bytes: Vec<u8> = (0..16u32 << 20).map(|i| (i * 7 + i / 251) as u8).collect();
+	let megabytes = bytes.len() as f64 / 1e6;
+	let rate = |start: Instant| megabytes / start.elapsed().as_secs_f64();
+
+	let start = Instant::now();
+	let bits: Vec<bool> = bytes.iter().map(|b| format!("{b:08b}")).collect::<String>().chars().map(|c| c == '1').collect();
+	let string = rate(start);
+
+	let start = Instant::now();
+	let packed_bits: Vec<bool> = (0..bytes.len() * 8).map(|i| get_bit(&bytes, i)).collect();
+	let packed = rate(start);
+	assert_eq!(bits, packed_bits);
+	println!("bytes -> bits: string {string:.1} MB/s, packed {packed:.1} MB/s ({:.0}x)", packed / string);
+
+	let start = Instant::now();
+	let string_bytes: Vec<u8> = bits
+	    .chunks(8)
+	    .map(|byte| u8::from_str_radix(&byte.iter().map(|b| if *b { '1' } else { '0' }).collect::<String>(), 2).unwrap())
+	    .collect();
+	let string = rate(start);
+
+	let start = Instant::now();
+	let mut buffer = BitBuffer::with_capacity(bits.len());
+	for bit in black_box(&bits) {
+	    buffer.push(*bit);
+	}
+	let packed_bytes = buffer.into_bytes();
+	let packed = rate(start);
+	assert_eq!(string_bytes, packed_bytes);
+	println!("bits -> bytes: string {string:.1} MB/s, packed {packed:.1} MB/s ({:.0}x)", packed / string);
+    }
 }
@@ -1,131 +1,831 @@
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::collections::VecDeque;
+use std::io::{self, Read};
 use std::os::unix::prelude::FileExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::{HEAD_LENGHT, VideoType, max_pixel_size, error, parse_headpage, pages_to_bytes};
+use crossbeam::{channel, thread};
 
-use indicatif::{ProgressBar, ProgressStyle, ProgressIterator};
+use crate::{VideoInfo, VideoType, Hasher, Image, VideoSource, Backend, Progress, Error, Result, parse_headpage, to_hex, open_source, BitBuffer, Sampler, Thresholds, Layout, Homography, locate, bgr_to_yuv, scramble, to_gray};
+use crate::sample::{level, bit_distance};
+use crate::layout::Cell;
 
-use opencv::prelude::MatTraitConst;
-use opencv::videoio::{self, VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst};
-use opencv::core::Mat;
+/// extracts files from videos made by an `Encoder`
+#[derive(Default)]
+pub struct Decoder {
+    output: Option<String>,
+    backend: Backend,
+    progress: Option<Progress>,
+}
 
-pub fn decode_video(filename: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut video = VideoCapture::from_file(filename, videoio::CAP_ANY)?;
-    let mut frame = Mat::default();
+/// result of a successful extraction
+#[derive(Debug, Clone)]
+pub struct Extracted {
+    /// path of the extracted file
+    pub path: String,
+    /// checksum of the extracted file, matching the one stored in the video
+    pub checksum: String,
+}
 
-    let width = video.get(videoio::CAP_PROP_FRAME_WIDTH)? as u32;
-    let height = video.get(videoio::CAP_PROP_FRAME_HEIGHT)? as u32;
+impl Decoder {
+    pub fn new() -> Self {
+	Self::default()
+    }
 
-    let pixel_size = max_pixel_size(HEAD_LENGHT, width, height) as u32;
+    /// write the extracted file to `path` instead of the file name stored in the video
+    pub fn output(mut self, path: &str) -> Self {
+	self.output = Some(path.to_string());
+	self
+    }
 
-    video.read(&mut frame)?;
+    /// what reads compressed videos, opencv by default when the feature is enabled
+    pub fn backend(mut self, backend: Backend) -> Self {
+	self.backend = backend;
+	self
+    }
 
-    let bits = decode_black_and_white(&frame, width, height, pixel_size)?;
-    let mut info = parse_headpage(&bits);
+    /// called after every decoded frame
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+	F: Fn(usize, usize) + Send + Sync + 'static,
+    {
+	self.progress = Some(Box::new(callback));
+	self
+    }
 
-    info.width = Some(width);
-    info.height = Some(height);
+    /// extracts the file stored in the video at `input`
+    pub fn decode(&self, input: &str) -> Result<Extracted> {
+	decode_video(input, self.output.as_deref(), &self.backend, self.progress.as_ref())
+    }
+}
 
-    println!("extracting {}", info.filename());
-    
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(info.filename())?;
+/// data of a frame along with the confidence of each of its bits
+#[derive(Debug, Clone)]
+pub struct SoftFrame {
+    pub bytes: Vec<u8>,
+    /// one value per bit of `bytes`, most significant bit first: how far the sampled level
+    /// was from the threshold deciding the bit, 0 means it could have gone either way
+    pub confidence: Vec<u8>,
+}
 
-    let mut offset = 0u64;
-    let bits_per_page = info.bytes_per_frame() * 8;
-
-    let pb = ProgressBar::new(info.total_frames() as u64);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len}, ETA {eta})",
-        )?,
-    );
-
-    for i in (0..info.total_frames()).progress_with(pb) {
-	video.read(&mut frame)?;
-
-	let mut buffer = match info.video_type {
-	    VideoType::BlackNWhite => decode_black_and_white(&frame, width, height, info.pixel_size as u32)?,
-	    VideoType::Color => decode_color(&frame, width, height, info.pixel_size as u32)?,
-	    _ => error("not yet implemented")
+/// streams the file stored in a video, frame by frame, reading past the end fails
+/// if the streamed data does not match the checksum stored in the video
+pub struct DecodeReader {
+    video: Box<dyn VideoSource>,
+    info: VideoInfo,
+    /// info stored in the last frame, once it has been read
+    trailer: Option<VideoInfo>,
+    /// frames read ahead to find the trailer frame of streamed videos
+    lookahead: VecDeque<Image>,
+    frames_read: usize,
+    hasher: Hasher,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl DecodeReader {
+    /// opens the video at `path` with `backend` and parses its head frame
+    pub fn open(path: &str, backend: &Backend) -> Result<Self> {
+	Self::new(open_source(path, backend)?)
+    }
+
+    /// parses the head frame of `video`, the next read frame must be the head frame
+    pub fn new(mut video: Box<dyn VideoSource>) -> Result<Self> {
+	let frame = match video.read()? {
+	    Some(f) => f,
+	    None => return Err(Error::Header("the video has no frame to read".to_string())),
 	};
 
-	assert!(buffer.len() == bits_per_page as usize);
+	let info = decode_headframe(&frame)?;
+	// the head frame declares which algorithm the checksum was computed with
+	let hasher = Hasher::new(info.hash);
+
+	Ok(Self {
+	    video,
+	    info,
+	    trailer: None,
+	    lookahead: VecDeque::new(),
+	    frames_read: 0,
+	    hasher,
+	    buffer: Vec::new(),
+	    pos: 0,
+	})
+    }
+
+    /// name of the encoded file, as stored in the head frame
+    pub fn filename(&self) -> &str {
+	self.info.filename.as_deref().unwrap_or_default()
+    }
+
+    /// hex checksum of the encoded file, unknown until the trailer frame has been read
+    pub fn checksum(&self) -> Option<String> {
+	self.trailer.as_ref().and_then(|info| info.checksum.as_deref()).map(to_hex)
+    }
+
+    /// number of frames holding data, unknown until the trailer of a streamed video has been read
+    pub fn total_frames(&self) -> Option<usize> {
+	if self.info.streamed {
+	    self.trailer.as_ref().and_then(|info| info.total_frames)
+	} else {
+	    self.info.total_frames
+	}
+    }
+
+    /// decodes the next frame along with the confidence of every bit, for an error correcting
+    /// layer to mark erasures or to decode softly. The checksum is verified like with `read`,
+    /// whose current frame must be read to the end before this is called
+    pub fn read_soft_frame(&mut self) -> Result<Option<SoftFrame>> {
+	if self.pos != self.buffer.len() {
+	    return Err(Error::Parameter("the frame taken by read is not read to the end".to_string()));
+	}
+
+	let frame = self.next_frame(true)?;
+	Ok(frame.map(|(bytes, confidence)| SoftFrame { bytes, confidence }))
+    }
+
+    /// decodes the next frame, the padding of the last frame is trimmed and
+    /// the checksum is verified once every frame has been read. The confidence
+    /// of the bits is empty unless `soft` is set
+    fn next_frame(&mut self, soft: bool) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+	if self.trailer.is_some() {
+	    return Ok(None);
+	}
+
+	let bytes = if self.info.streamed {
+	    self.next_streamed_frame(soft)?
+	} else {
+	    self.next_counted_frame(soft)?
+	};
+
+	match &bytes {
+	    Some((b, _)) => self.hasher.update(b),
+	    None => self.verify()?,
+	}
+
+	// the trailer may have been read along the last frame
+	if self.trailer.is_some() && bytes.is_some() {
+	    self.verify()?;
+	}
+
+	Ok(bytes)
+    }
+
+    fn verify(&mut self) -> Result<()> {
+	let hasher = std::mem::replace(&mut self.hasher, Hasher::new(self.info.hash));
+	let found = to_hex(&hasher.finalize());
+	let expected = self.checksum().unwrap_or_default();
+
+	if found != expected {
+	    return Err(Error::Integrity { path: self.filename().to_string(), expected, found });
+	}
+
+	Ok(())
+    }
+
+    fn next_counted_frame(&mut self, soft: bool) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+	let total_frames = self.info.total_frames()?;
+
+	let frame = match self.video.read()? {
+	    Some(f) => f,
+	    None if self.frames_read == total_frames => {
+		return Err(Error::Header("the trailer frame of the video is missing".to_string()));
+	    },
+	    None => return Err(Error::Header(format!(
+		"the video ends after {} frames but {total_frames} were expected",
+		self.frames_read
+	    ))),
+	};
+
+	if self.frames_read == total_frames {
+	    self.read_trailer(&frame)?;
+	    return Ok(None);
+	}
+
+	self.frames_read += 1;
+	let unused_bytes = if self.frames_read == total_frames { self.info.unused_bytes()? } else { 0 };
+
+	decode_frame(&frame, self.frames_read - 1, &self.info, unused_bytes, soft).map(Some)
+    }
+
+    /// the last frame of a streamed video is the trailer, so a frame is only known
+    /// to hold data once the two frames following it have been read
+    fn next_streamed_frame(&mut self, soft: bool) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+	while self.lookahead.len() < 3 {
+	    match self.video.read()? {
+		Some(f) => self.lookahead.push_back(f),
+		None => break,
+	    }
+	}
+
+	match self.lookahead.pop_front() {
+	    None => Err(Error::Header("the trailer frame of the streamed video is missing".to_string())),
+	    Some(trailer) if self.lookahead.is_empty() => {
+		self.read_trailer(&trailer)?;
+		Ok(None)
+	    },
+	    Some(frame) => {
+		self.frames_read += 1;
+
+		if self.lookahead.len() == 1 {
+		    let trailer = self.lookahead.pop_front().unwrap();
+		    self.read_trailer(&trailer)?;
+		}
+
+		let unused_bytes = match &mut self.trailer {
+		    Some(t) => t.unused_bytes()?,
+		    None => 0,
+		};
 
-	if i == info.total_frames() -1 {
-	    buffer.truncate(buffer.len() - (info.unused_bytes() * 8) as usize);
+		decode_frame(&frame, self.frames_read - 1, &self.info, unused_bytes, soft).map(Some)
+	    },
+	}
+    }
+
+    fn read_trailer(&mut self, frame: &Image) -> Result<()> {
+	let mut trailer = decode_headframe(frame)?;
+
+	let total_frames = trailer.total_frames()?;
+	if total_frames != self.frames_read {
+	    return Err(Error::Header(format!(
+		"the trailer frame expects {total_frames} frames but the video holds {}",
+		self.frames_read
+	    )));
 	}
 
-	file.write_all_at(&pages_to_bytes(&buffer), offset)?;
-	offset += bits_per_page as u64 /8;
+	self.trailer = Some(trailer);
+	Ok(())
     }
+}
+
+impl Read for DecodeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	while self.pos == self.buffer.len() {
+	    match self.next_frame(false) {
+		Ok(Some((bytes, _))) => {
+		    self.buffer = bytes;
+		    self.pos = 0;
+		},
+		Ok(None) => return Ok(0),
+		Err(Error::Io(e)) => return Err(e),
+		Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+	    }
+	}
 
-    let filename = info.filename();
-    let path = std::path::Path::new(&filename);
-    let new_checksum = match sha256::try_digest(path) {
-	Ok(s) => s,
-	Err(_) => error("failed to get extracted file's checksum")
+	let n = buf.len().min(self.buffer.len() - self.pos);
+	buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+	self.pos += n;
+
+	Ok(n)
+    }
+}
+
+pub fn decode_video(filename: &str, output: Option<&str>, backend: &Backend, progress: Option<&Progress>) -> Result<Extracted> {
+    let mut reader = DecodeReader::open(filename, backend)?;
+
+    let path = match output {
+	Some(o) => o.to_string(),
+	None => reader.filename().to_string(),
     };
 
-    if new_checksum == info.checksum.unwrap() {
-	println!("extracted file is intact");
+    let file = OpenOptions::new()
+	.create(true)
+	.truncate(true)
+	.write(true)
+	.open(&path)?;
+
+    // the length of streamed videos is only known once their trailer is read
+    let (expected, found) = if reader.info.streamed {
+	decode_sequential(&mut reader, &file, progress)?
     } else {
-	println!("extracted file is corrupted");
-    }
-
-    Ok(())
-}
-
-fn decode_black_and_white(frame: &Mat, width: u32, height: u32, pixel_size: u32) -> Result<Vec<bool>, Box<dyn std::error::Error>> {
-    let mut bits = Vec::new();
-    for i in 0..(height / pixel_size) {
-	for j in 0..(width / pixel_size) {
-	    let x = (i * pixel_size + pixel_size /2) as i32;
-	    let y = (j * pixel_size + pixel_size /2) as i32;
-	    let bgr = frame.at_2d::<opencv::core::Vec3b>(x, y)?;
-	    
-	    let avg: u32 = bgr.iter().map(|c| *c as u32).sum::<u32>() / 3;
-	    
-	    if avg >= 128 {
-		bits.push(true);
-	    } else {
-		bits.push(false);
+	decode_segments(filename, backend, &reader.info, &file, progress)?
+    };
+
+    if found != expected {
+	return Err(Error::Integrity { path, expected, found });
+    }
+
+    Ok(Extracted { path, checksum: found })
+}
+
+/// returns the expected and the computed checksums
+fn decode_sequential(reader: &mut DecodeReader, file: &File, progress: Option<&Progress>) -> Result<(String, String)> {
+    let mut offset = 0u64;
+    let mut i = 0;
+
+    loop {
+	// the reader verifies the checksum once it has read every frame
+	let bytes = match reader.next_frame(false) {
+	    Ok(Some((b, _))) => b,
+	    Ok(None) => break,
+	    Err(Error::Integrity { expected, found, .. }) => return Ok((expected, found)),
+	    Err(e) => return Err(e),
+	};
+
+	file.write_all_at(&bytes, offset)?;
+	offset += bytes.len() as u64;
+
+	i += 1;
+	if let Some(callback) = progress {
+	    callback(i, reader.total_frames().unwrap_or(0));
+	}
+    }
+
+    let checksum = reader.checksum().unwrap_or_default();
+    Ok((checksum.clone(), checksum))
+}
+
+/// frames of a range handed to a worker, two keyframe intervals of common encoders: a worker
+/// seeks once per range, which decodes again the frames since the previous keyframe
+const RANGE_FRAMES: usize = 500;
+
+/// the frames are split in contiguous ranges handed to the workers in turn, each seeks its own
+/// capture to the start of its ranges and writes the decoded frames at their offset while the
+/// calling thread hashes them in order as they come
+fn decode_segments(filename: &str, backend: &Backend, info: &VideoInfo, file: &File, progress: Option<&Progress>) -> Result<(String, String)> {
+    let mut info = info.clone();
+    let total_frames = info.total_frames()?;
+    let unused_bytes = info.unused_bytes()?;
+    let bytes_per_frame = info.bytes_per_frame()? as u64;
+
+    let ranges = total_frames.div_ceil(RANGE_FRAMES);
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get()).min(ranges).max(1);
+
+    let info = &info;
+    let done = &AtomicUsize::new(0);
+
+    let found = thread::scope(|s| -> Result<String> {
+	// one channel per worker so that the frames can be taken in order,
+	// a worker can only get one range ahead of the hasher
+	let (txs, rxs): (Vec<_>, Vec<_>) = (0..workers).map(|_| channel::bounded::<Vec<u8>>(RANGE_FRAMES)).unzip();
+
+	let handles: Vec<_> = txs.into_iter().enumerate().map(|(w, tx)| {
+	    s.spawn(move |_| -> Result<()> {
+		let mut video = open_source(filename, backend)?;
+
+		for range in (w..ranges).step_by(workers) {
+		    let start = range * RANGE_FRAMES;
+		    let end = (start + RANGE_FRAMES).min(total_frames);
+
+		    // the head frame comes first
+		    video.seek(start + 1)?;
+
+		    for i in start..end {
+			let frame = match video.read()? {
+			    Some(f) => f,
+			    None => return Err(Error::Header(format!("the video ends after {i} frames but {total_frames} were expected"))),
+			};
+
+			let unused = if i == total_frames - 1 { unused_bytes } else { 0 };
+			let (bytes, _) = decode_frame(&frame, i, info, unused, false)?;
+			file.write_all_at(&bytes, i as u64 * bytes_per_frame)?;
+
+			// the hasher stopped because another worker failed
+			if tx.send(bytes).is_err() {
+			    return Ok(());
+			}
+
+			let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+			if let Some(callback) = progress {
+			    callback(n, total_frames);
+			}
+		    }
+		}
+
+		Ok(())
+	    })
+	}).collect();
+
+	let mut hasher = Hasher::new(info.hash);
+	for i in 0..total_frames {
+	    match rxs[(i / RANGE_FRAMES) % workers].recv() {
+		Ok(bytes) => hasher.update(&bytes),
+		Err(_) => break,
 	    }
 	}
+	drop(rxs);
+
+	handles.into_iter().try_for_each(|h| h.join().unwrap())?;
+	Ok(to_hex(&hasher.finalize()))
+    }).unwrap()?;
+
+    let mut video = open_source(filename, backend)?;
+
+    video.seek(total_frames + 1)?;
+    let frame = match video.read()? {
+	Some(f) => f,
+	None => return Err(Error::Header("the trailer frame of the video is missing".to_string())),
+    };
+
+    let trailer = decode_headframe(&frame)?;
+    Ok((to_hex(&trailer.checksum.unwrap_or_default()), found))
+}
+
+/// decodes `frame`, the data frame at `index`, and drops the `unused_bytes` of padding at its end,
+/// the confidence of every bit is given back along when `soft` is set
+fn decode_frame(frame: &Image, index: usize, info: &VideoInfo, unused_bytes: u32, soft: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    // the video may have been rescaled since it was encoded
+    let layout = info.layout()?.scaled_to(frame.width, frame.height);
+    if !layout.fits() {
+	return Err(Error::Parameter(format!(
+	    "the blocks of the {}x{} video are smaller than a pixel once rescaled to {}x{}",
+	    layout.width, layout.height, frame.width, frame.height
+	)));
     }
 
-    Ok(bits)
+    // the grid may have been shifted or padded as well
+    let mapping = locate(frame, &layout);
+
+    let mut bits = BitBuffer::soft(info.video_type.bits_per_frame(&layout) as usize, soft);
+
+    match info.video_type {
+	VideoType::BlackNWhite => decode_black_and_white(frame, &layout, &mapping, &mut bits),
+	VideoType::Color => decode_color(frame, &layout, &mapping, &mut bits),
+	VideoType::Chroma420 => decode_chroma420(frame, &layout, &mapping, &mut bits),
+	VideoType::GrayScale => decode_levels(frame, &layout, &mapping, (1, 3), info.gray_code, &mut bits),
+	VideoType::Color8 => decode_levels(frame, &layout, &mapping, (3, 3), info.gray_code, &mut bits),
+	VideoType::Color16 => decode_levels(frame, &layout, &mapping, (3, 4), info.gray_code, &mut bits),
+    }
+
+    // the blocks left after the last full byte hold nothing
+    let bytes_per_frame = info.bytes_per_frame()? as usize;
+    let (mut buffer, mut confidence) = bits.into_parts();
+    buffer.truncate(bytes_per_frame);
+
+    let used = bytes_per_frame.checked_sub(unused_bytes as usize).ok_or_else(|| {
+	Error::Header(format!("{unused_bytes} unused bytes do not fit in a frame of {bytes_per_frame} bytes"))
+    })?;
+
+    scramble(&mut buffer, info.seed, index);
+    buffer.truncate(used);
+    confidence.truncate(buffer.len() * 8);
+
+    Ok((buffer, confidence))
+}
+
+/// the head frame is stretched over the whole frame, so it reads the same at any resolution
+fn decode_headframe(frame: &Image) -> Result<VideoInfo> {
+    let layout = Layout::head(frame.width, frame.height);
+    if !layout.fits() {
+	return Err(Error::Header(format!("a {}x{} video is too small to hold a head frame", frame.width, frame.height)));
+    }
+
+    let mut bits = BitBuffer::with_capacity(layout.data_blocks() as usize);
+    decode_black_and_white(frame, &layout, &locate(frame, &layout), &mut bits);
+    parse_headpage(&bits.into_bytes())
 }
 
-fn decode_color(frame: &Mat, width: u32, height: u32, pixel_size: u32) -> Result<Vec<bool>, Box<dyn std::error::Error>> {
-    let mut bits = Vec::new();
-    for i in 0..(height / pixel_size) {
-	for j in 0..(width / pixel_size) {
-	    let x = (i * pixel_size + pixel_size /2) as i32;
-	    let y = (j * pixel_size + pixel_size /2) as i32;
-	    let bgr = frame.at_2d::<opencv::core::Vec3b>(x, y)?;
-	    
-	    if bgr[2] >= 128 {
-		bits.push(true);
-	    } else {
-		bits.push(false);
+fn decode_black_and_white(frame: &Image, layout: &Layout, mapping: &Homography, bits: &mut BitBuffer) {
+    let mut sampler = Sampler::new();
+    let thresholds = Thresholds::measure(frame, layout, mapping, &mut sampler);
+
+    for block in layout.data_cells() {
+	let bgr = sampler.block(frame, mapping, block);
+
+	let avg = (bgr.iter().map(|c| *c as u32).sum::<u32>() / 3) as u8;
+	bits.push_soft(avg >= thresholds.gray, avg.abs_diff(thresholds.gray));
+    }
+}
+
+fn decode_color(frame: &Image, layout: &Layout, mapping: &Homography, bits: &mut BitBuffer) {
+    let mut sampler = Sampler::new();
+    let thresholds = Thresholds::measure(frame, layout, mapping, &mut sampler);
+
+    for block in layout.data_cells() {
+	let bgr = sampler.block(frame, mapping, block);
+
+	for c in [2, 1, 0] {
+	    bits.push_soft(bgr[c] >= thresholds.bgr[c], bgr[c].abs_diff(thresholds.bgr[c]));
+	}
+    }
+}
+
+fn decode_chroma420(frame: &Image, layout: &Layout, mapping: &Homography, bits: &mut BitBuffer) {
+    let squares = layout.chroma_squares();
+    let mut sampler = Sampler::new();
+    let thresholds = Thresholds::measure(frame, layout, mapping, &mut sampler);
+
+    // chroma summed over the blocks of every square, read after the luma of every block
+    let mut chroma = vec![(0, 0); squares.count()];
+
+    for (column, row, cell) in layout.cells() {
+	if !matches!(cell, Cell::Data(_)) {
+	    continue;
+	}
+
+	let [y, u, v] = bgr_to_yuv(&sampler.block(frame, mapping, layout.block(column, row)));
+	bits.push_soft(y >= thresholds.yuv[0], y.abs_diff(thresholds.yuv[0]));
+
+	if let Some(s) = squares.get(column, row) {
+	    chroma[s] = (chroma[s].0 + u as u32, chroma[s].1 + v as u32);
+	}
+    }
+
+    for (u, v) in chroma {
+	for (c, threshold) in [(u / 4, thresholds.yuv[1]), (v / 4, thresholds.yuv[2])] {
+	    let c = c as u8;
+	    bits.push_soft(c >= threshold, c.abs_diff(threshold));
+	}
+    }
+}
+
+/// reads the level of the `channels` of every block, each gives back `bits` bits
+fn decode_levels(frame: &Image, layout: &Layout, mapping: &Homography, (channels, bits): (usize, usize), gray_code: bool, buffer: &mut BitBuffer) {
+    let symbol = |level: u32| if gray_code { to_gray(level) } else { level };
+    let mut sampler = Sampler::new();
+    let thresholds = Thresholds::measure(frame, layout, mapping, &mut sampler);
+
+    for block in layout.data_cells() {
+	let bgr = sampler.block(frame, mapping, block);
+
+	for c in 0..channels {
+	    let (value, range) = match channels {
+		1 => ((bgr.iter().map(|c| *c as u32).sum::<u32>() / 3) as u8, thresholds.gray_range),
+		_ => (bgr[2 - c], thresholds.ranges[2 - c]),
+	    };
+
+	    let found = level(value, range, 1 << bits);
+	    for bit in (0..bits).rev() {
+		// looking through the other levels is only worth it when the confidence is kept
+		let confidence = if buffer.is_soft() { bit_distance(value, range, 1 << bits, found, bit, symbol) } else { 0 };
+		buffer.push_soft(symbol(found) >> bit & 1 == 1, confidence);
 	    }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Encoder, Decoder, DecodeReader, VideoType, Backend, Codec, Image, VideoSink, Layout, FfmpegOptions, Error, open_sink, open_source};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::cell::Cell;
+    use std::process::Command;
+
+    /// a temporary directory holding a few frames worth of data, removed once the test ends
+    struct Sample {
+	dir: PathBuf,
+	data: Vec<u8>,
+    }
+
+    impl Sample {
+	fn new(name: &str) -> Self {
+	    let dir = std::env::temp_dir().join(format!("ytstorage-{}-{name}", std::process::id()));
+	    fs::create_dir_all(&dir).unwrap();
+
+	    let data: Vec<u8> = (0..20_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+	    fs::write(dir.join("data.bin"), &data).unwrap();
+
+	    Self { dir, data }
+	}
+
+	fn path(&self, file: &str) -> String {
+	    self.dir.join(file).to_str().unwrap().to_string()
+	}
 
-	    if bgr[1] >= 128 {
-		bits.push(true);
-	    } else {
-		bits.push(false);
+	/// encodes the data to `video` with the settings of `encoder`
+	fn encode(&self, encoder: Encoder, video: &str) {
+	    encoder.encode(&self.path("data.bin"), &self.path(video)).unwrap();
+	}
+
+	/// extracts `video` and checks that it gives the data back
+	fn assert_extracts(&self, video: &str) {
+	    Decoder::new().output(&self.path("out.bin")).decode(&self.path(video)).unwrap();
+	    assert_eq!(fs::read(self.path("out.bin")).unwrap(), self.data);
+	}
+
+	/// rewrites every frame of `from` to `to` through `f`, like a re-upload would
+	fn transform(&self, from: &str, to: &str, f: impl Fn(&Image) -> Image) {
+	    let mut source = open_source(&self.path(from), &Backend::default()).unwrap();
+	    let mut sink: Option<Box<dyn VideoSink>> = None;
+
+	    while let Some(frame) = source.read().unwrap() {
+		let frame = f(&frame);
+		let sink = sink.get_or_insert_with(|| {
+		    open_sink(&self.path(to), None, &Backend::default(), 30, frame.width, frame.height).unwrap()
+		});
+		sink.write(&frame).unwrap();
 	    }
+	    sink.unwrap().finish().unwrap();
+	}
+    }
+
+    impl Drop for Sample {
+	fn drop(&mut self) {
+	    let _ = fs::remove_dir_all(&self.dir);
+	}
+    }
+
+    fn encoder(video_type: VideoType) -> Encoder {
+	Encoder::new().video_type(video_type).pixel_size(2).resolution(320, 180)
+    }
+
+    /// encodes a few frames of data to a y4m file, which is lossless, and extracts them back
+    fn round_trip(video_type: VideoType, name: &str) {
+	let sample = Sample::new(name);
+	sample.encode(encoder(video_type), "video.y4m");
+	sample.assert_extracts("video.y4m");
+    }
 
-	    if bgr[0] >= 128 {
-		bits.push(true);
-	    } else {
-		bits.push(false);
+    /// `frame` in the middle of black bars, `left` and `top` pixels away from the corner
+    fn letterbox(frame: &Image, width: u32, height: u32, left: u32, top: u32) -> Image {
+	let mut boxed = Image::new(width, height);
+	let row = (frame.width * 3) as usize;
+
+	for y in 0..frame.height {
+	    let from = (y * frame.width * 3) as usize;
+	    let to = (((y + top) * width + left) * 3) as usize;
+	    boxed.data[to..to + row].copy_from_slice(&frame.data[from..from + row]);
+	}
+
+	boxed
+    }
+
+    /// `frame` resized to `width` by `height` with bilinear interpolation, as most players and
+    /// re-encodes do
+    fn rescale(frame: &Image, width: u32, height: u32) -> Image {
+	// the two source pixels around each destination one and the weight of the second
+	let around = |i: u32, to: u32, from: u32| {
+	    let x = ((i as f64 + 0.5) * from as f64 / to as f64 - 0.5).clamp(0.0, (from - 1) as f64);
+	    (x as u32, (x as u32 + 1).min(from - 1), x.fract())
+	};
+
+	let mut scaled = Image::new(width, height);
+	for y in 0..height {
+	    let (y0, y1, fy) = around(y, height, frame.height);
+	    for x in 0..width {
+		let (x0, x1, fx) = around(x, width, frame.width);
+		let (a, b, c, d) = (frame.pixel(x0, y0), frame.pixel(x1, y0), frame.pixel(x0, y1), frame.pixel(x1, y1));
+
+		let i = ((y * width + x) * 3) as usize;
+		for k in 0..3 {
+		    let top = a[k] as f64 * (1.0 - fx) + b[k] as f64 * fx;
+		    let bottom = c[k] as f64 * (1.0 - fx) + d[k] as f64 * fx;
+		    scaled.data[i + k] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+		}
 	    }
 	}
+
+	scaled
+    }
+
+    #[test]
+    fn black_and_white_round_trips() {
+	round_trip(VideoType::BlackNWhite, "black");
+    }
+
+    #[test]
+    fn color_round_trips() {
+	round_trip(VideoType::Color, "color");
+    }
+
+    #[test]
+    fn chroma420_round_trips() {
+	round_trip(VideoType::Chroma420, "chroma420");
+    }
+
+    #[test]
+    fn grayscale_round_trips() {
+	round_trip(VideoType::GrayScale, "grayscale");
+    }
+
+    #[test]
+    fn color8_round_trips() {
+	round_trip(VideoType::Color8, "color8");
+    }
+
+    #[test]
+    fn color16_round_trips() {
+	round_trip(VideoType::Color16, "color16");
+    }
+
+    #[test]
+    fn padded_and_shifted_frames_are_located() {
+	let sample = Sample::new("letterbox");
+	sample.encode(encoder(VideoType::BlackNWhite), "video.y4m");
+	sample.transform("video.y4m", "boxed.y4m", |frame| letterbox(frame, 352, 200, 21, 7));
+	sample.assert_extracts("boxed.y4m");
+    }
+
+    #[test]
+    fn downscaled_frames_are_located() {
+	let sample = Sample::new("rescale");
+	sample.encode(Encoder::new().pixel_size(4).resolution(640, 360), "video.y4m");
+	sample.transform("video.y4m", "scaled.y4m", |frame| rescale(frame, 427, 240));
+	sample.assert_extracts("scaled.y4m");
+    }
+
+    #[test]
+    fn a_margin_around_blocks_that_do_not_tile_round_trips() {
+	let sample = Sample::new("margin");
+	sample.encode(Encoder::new().pixel_size(3).resolution(320, 181).margin(7), "video.y4m");
+	sample.assert_extracts("video.y4m");
+    }
+
+    #[test]
+    fn blocks_that_are_not_square_round_trip() {
+	let sample = Sample::new("rectangles");
+	sample.encode(encoder(VideoType::Color).pixel_width(3).pixel_height(2).margin(5), "video.y4m");
+	sample.transform("video.y4m", "boxed.y4m", |frame| letterbox(frame, 336, 192, 4, 9));
+	sample.assert_extracts("boxed.y4m");
+    }
+
+    #[test]
+    fn an_explicit_codec_is_refused_for_uncompressed_outputs() {
+	let sample = Sample::new("codec");
+	let encoded = encoder(VideoType::BlackNWhite).codec(Codec::H264).encode(&sample.path("data.bin"), &sample.path("video.y4m"));
+	assert!(matches!(encoded, Err(Error::Parameter(_))));
+    }
+
+    #[test]
+    fn a_directory_of_images_round_trips() {
+	let sample = Sample::new("images");
+	sample.encode(encoder(VideoType::Color), "frames");
+	assert!(fs::read_dir(sample.path("frames")).unwrap().count() > 1);
+	sample.assert_extracts("frames");
+    }
+
+    /// only runs where ffmpeg is installed
+    #[test]
+    fn a_video_compressed_by_ffmpeg_round_trips() {
+	if Command::new("ffmpeg").arg("-version").output().is_err() {
+	    eprintln!("ffmpeg is not in the PATH, skipped");
+	    return;
+	}
+
+	let sample = Sample::new("ffmpeg");
+	let ffmpeg = Backend::Ffmpeg(FfmpegOptions::default());
+	sample.encode(Encoder::new().pixel_size(4).resolution(640, 360).backend(ffmpeg.clone()), "video.mp4");
+
+	Decoder::new().backend(ffmpeg).output(&sample.path("out.bin")).decode(&sample.path("video.mp4")).unwrap();
+	assert_eq!(fs::read(sample.path("out.bin")).unwrap(), sample.data);
+    }
+
+    #[test]
+    fn streamed_data_round_trips() {
+	let sample = Sample::new("streamed");
+	let mut writer = encoder(VideoType::BlackNWhite).writer("data.bin", &sample.path("video.y4m")).unwrap();
+	for chunk in sample.data.chunks(777) {
+	    writer.write_all(chunk).unwrap();
+	}
+	writer.finish().unwrap();
+
+	sample.assert_extracts("video.y4m");
     }
 
-    Ok(bits)
+    #[test]
+    fn an_empty_stream_round_trips() {
+	let sample = Sample::new("empty");
+	encoder(VideoType::BlackNWhite).writer("data.bin", &sample.path("video.y4m")).unwrap().finish().unwrap();
+
+	Decoder::new().output(&sample.path("out.bin")).decode(&sample.path("video.y4m")).unwrap();
+	assert!(fs::read(sample.path("out.bin")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn soft_frames_can_not_start_in_the_middle_of_a_frame() {
+	let sample = Sample::new("soft");
+	sample.encode(encoder(VideoType::BlackNWhite), "video.y4m");
+
+	let mut reader = DecodeReader::open(&sample.path("video.y4m"), &Backend::default()).unwrap();
+	reader.read_exact(&mut [0; 1]).unwrap();
+	assert!(matches!(reader.read_soft_frame(), Err(Error::Parameter(_))));
+    }
+
+    /// confidence of the bits of the first data frame once its first block is turned to the
+    /// gray sitting on the threshold, split between the bits of that block and the others
+    fn confidence_around_a_gray_block(video_type: VideoType, name: &str, bits_per_block: usize) -> (u8, u8) {
+	let sample = Sample::new(name);
+	sample.encode(encoder(video_type), "video.y4m");
+
+	let block = Layout::new(320, 180, 2, 2).data_cells().next().unwrap();
+	let index = Cell::new(0);
+	sample.transform("video.y4m", "gray.y4m", |frame| {
+	    let mut frame = frame.clone();
+	    if index.replace(index.get() + 1) == 1 {
+		for y in block.y0..block.y1 {
+		    let row = ((y * frame.width + block.x0) * 3) as usize;
+		    frame.data[row..row + ((block.x1 - block.x0) * 3) as usize].fill(128);
+		}
+	    }
+	    frame
+	});
+
+	let mut reader = DecodeReader::open(&sample.path("gray.y4m"), &Backend::default()).unwrap();
+	let frame = reader.read_soft_frame().unwrap().unwrap();
+	let (gray, clean) = frame.confidence.split_at(bits_per_block);
+
+	(*gray.iter().min().unwrap(), *clean.iter().min().unwrap())
+    }
+
+    #[test]
+    fn confidence_drops_on_a_block_read_at_the_threshold() {
+	let (gray, clean) = confidence_around_a_gray_block(VideoType::BlackNWhite, "confidence-black", 1);
+	assert!(gray <= 2, "{gray}");
+	assert!(clean >= 100, "{clean}");
+
+	let (gray, clean) = confidence_around_a_gray_block(VideoType::GrayScale, "confidence-grayscale", 3);
+	assert!(gray <= 2, "{gray}");
+	assert!(clean >= 12, "{clean}");
+    }
 }
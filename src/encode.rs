@@ -1,64 +1,409 @@
-use crate::{HEAD_LENGHT, VideoInfo, VideoType, Frame, max_pixel_size, error, gen_headpage, byte_to_bits};
+use crate::{VideoInfo, VideoType, HashAlgorithm, Codec, Backend, Hasher, Frame, VideoSink, Progress, Error, Result, Layout, gen_headpage, open_sink, scramble, random_seed};
+use crate::layout::{HEAD_COLUMNS, HEAD_ROWS};
 
-use std::io::{Read, BufReader};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
 
-use indicatif::{ProgressIterator, ProgressBar, ProgressStyle};
+use crossbeam::{channel, thread};
 
-use crossbeam::thread;
 
-use opencv::videoio::{VideoWriter, VideoWriterTrait};
-use opencv::core::Size;
+/// converts files to videos, settings are chained before calling `encode`
+pub struct Encoder {
+    video_type: VideoType,
+    hash: HashAlgorithm,
+    /// the default one of compressed outputs when unset
+    codec: Option<Codec>,
+    backend: Backend,
+    pixel_width: u8,
+    pixel_height: u8,
+    margin: u32,
+    seed: Option<u32>,
+    gray_code: bool,
+    fps: u32,
+    width: u32,
+    height: u32,
+    progress: Option<Progress>,
+}
 
-pub fn create_video(mut info: VideoInfo, output: &str) {
-    let total_pixels = info.total_pixels();
-    let total_frames = info.total_frames();
+impl Default for Encoder {
+    fn default() -> Self {
+	Self {
+	    video_type: VideoType::BlackNWhite,
+	    hash: HashAlgorithm::Sha256,
+	    codec: None,
+	    backend: Backend::default(),
+	    pixel_width: 10,
+	    pixel_height: 10,
+	    margin: 0,
+	    seed: None,
+	    gray_code: true,
+	    fps: 24,
+	    width: 1280,
+	    height: 720,
+	    progress: None,
+	}
+    }
+}
 
-    if total_pixels % info.pixel_size as u32 != 0 {
-	error("pixel size, width or height are incompatible");
+impl Encoder {
+    pub fn new() -> Self {
+	Self::default()
     }
 
-    if total_pixels % 8 != 0 {
-	error("can't store bytes on a frame");
+    pub fn video_type(mut self, video_type: VideoType) -> Self {
+	self.video_type = video_type;
+	self
     }
 
-    let buf = BufReader::new(info.load_file());
-    let mut bytes = buf.bytes();
+    /// algorithm of the checksum stored in the trailer frame, decoding verifies with the same one
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+	self.hash = hash;
+	self
+    }
 
-    let fourcc = VideoWriter::fourcc('a', 'v', 'c', '1').unwrap();
-    let mut video = VideoWriter::new(output, fourcc, info.fps() as f64, Size::new(info.width() as i32, info.height() as i32), true).unwrap();
+    /// the container is taken from the extension of the output, it must be able to hold the codec,
+    /// `.y4m` files and directories of images are uncompressed and refuse any codec. H264 when unset
+    pub fn codec(mut self, codec: Codec) -> Self {
+	self.codec = Some(codec);
+	self
+    }
+
+    /// what compresses the video, opencv by default when the feature is enabled
+    pub fn backend(mut self, backend: Backend) -> Self {
+	self.backend = backend;
+	self
+    }
+
+    /// bigger pixel_size means less corruption on youtube but very large files
+    pub fn pixel_size(mut self, pixel_size: u8) -> Self {
+	self.pixel_width = pixel_size;
+	self.pixel_height = pixel_size;
+	self
+    }
+
+    /// width of the blocks, codecs and chroma subsampling do not always treat both axes the same
+    pub fn pixel_width(mut self, pixel_width: u8) -> Self {
+	self.pixel_width = pixel_width;
+	self
+    }
+
+    /// height of the blocks
+    pub fn pixel_height(mut self, pixel_height: u8) -> Self {
+	self.pixel_height = pixel_height;
+	self
+    }
+
+    /// pixels left empty on every side of the data, away from the edges where codecs do the worst
+    pub fn margin(mut self, margin: u32) -> Self {
+	self.margin = margin;
+	self
+    }
+
+    /// seed of the scrambler making the frames look like noise, a random one by default
+    pub fn seed(mut self, seed: u32) -> Self {
+	self.seed = Some(seed);
+	self
+    }
+
+    /// whether the levels of grayscale, color8 and color16 are gray coded, so that an error
+    /// of one level only costs one bit, on by default
+    pub fn gray_code(mut self, gray_code: bool) -> Self {
+	self.gray_code = gray_code;
+	self
+    }
+
+    pub fn fps(mut self, fps: u32) -> Self {
+	self.fps = fps;
+	self
+    }
+
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+	self.width = width;
+	self.height = height;
+	self
+    }
+
+    /// called after every written frame
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+	F: Fn(usize, usize) + Send + Sync + 'static,
+    {
+	self.progress = Some(Box::new(callback));
+	self
+    }
 
-    let head = gen_headpage(info.clone());
+    /// rejects the settings no video can be made with
+    fn check(&self) -> Result<()> {
+	if self.pixel_width == 0 || self.pixel_height == 0 {
+	    return Err(Error::Parameter("the blocks must be at least one pixel wide and high".to_string()));
+	}
+
+	if self.fps == 0 {
+	    return Err(Error::Parameter("the fps must be at least 1".to_string()));
+	}
+
+	Ok(())
+    }
+
+    /// encodes the file at `input` into a video written to `output`
+    pub fn encode(&self, input: &str, output: &str) -> Result<()> {
+	self.check()?;
+
+	let path = Path::new(input);
+	if !path.exists() {
+	    return Err(Error::Parameter(format!("{input} does not exist")));
+	}
+
+	let filename = match path.file_name().map(|f| f.to_str()) {
+	    Some(Some(f)) => f,
+	    Some(None) => return Err(Error::Parameter(format!("{input} is not a valid utf-8 file name"))),
+	    None => return Err(Error::Parameter(format!("{input} has no file name"))),
+	};
+
+	let file = File::open(path)?;
+	let mut info = VideoInfo::new(self.video_type, filename, Some(file.metadata()?.len()), (self.pixel_width, self.pixel_height), self.fps, self.width, self.height);
+	info.hash = self.hash;
+	info.margin = self.margin;
+	info.seed = self.seed.unwrap_or_else(random_seed);
+	info.gray_code = self.gray_code;
+
+	create_video(info, file, output, self.codec, &self.backend, self.progress.as_ref())
+    }
+
+    /// opens a video at `output` that can be written to without knowing the size of the data,
+    /// `filename` is stored in the video as the name of the file to extract
+    pub fn writer(&self, filename: &str, output: &str) -> Result<EncodeWriter> {
+	self.check()?;
+
+	let mut info = VideoInfo::new(self.video_type, filename, None, (self.pixel_width, self.pixel_height), self.fps, self.width, self.height);
+	info.hash = self.hash;
+	info.margin = self.margin;
+	info.seed = self.seed.unwrap_or_else(random_seed);
+	info.gray_code = self.gray_code;
+	EncodeWriter::new(info, output, self.codec, &self.backend)
+    }
+}
+
+/// streams data of unknown length into a video, `finish` must be called once everything is written
+pub struct EncodeWriter {
+    video: Box<dyn VideoSink>,
+    info: VideoInfo,
+    bytes_per_frame: usize,
+    buffer: Vec<u8>,
+    hasher: Hasher,
+    file_size: u64,
+    total_frames: usize,
+}
+
+impl EncodeWriter {
+    fn new(info: VideoInfo, output: &str, codec: Option<Codec>, backend: &Backend) -> Result<Self> {
+	let bytes_per_frame = info.bytes_per_frame()? as usize;
+	let mut video = open_video(&info, output, codec, backend)?;
+
+	// the real values are only known once everything is written, they go to the trailer frame
+	let mut head = info.clone();
+	head.unused_bytes = Some(0);
+	head.total_frames = Some(0);
+
+	write_headframe(video.as_mut(), gen_headpage(head)?, info.width()?, info.height()?)?;
+	let hasher = Hasher::new(info.hash);
+
+	Ok(Self {
+	    video,
+	    info,
+	    bytes_per_frame,
+	    buffer: Vec::with_capacity(bytes_per_frame),
+	    hasher,
+	    file_size: 0,
+	    total_frames: 0,
+	})
+    }
+
+    fn write_buffer(&mut self) -> Result<()> {
+	write_frame(self.video.as_mut(), self.buffer.clone(), self.total_frames, &self.info)?;
+
+	self.buffer.clear();
+	self.total_frames += 1;
+
+	Ok(())
+    }
 
-    let head_pixel = max_pixel_size(HEAD_LENGHT, info.width(), info.height());
+    /// writes the last frame and the trailer frame holding the size and the checksum of the data
+    pub fn finish(mut self) -> Result<()> {
+	if !self.buffer.is_empty() {
+	    self.write_buffer()?;
+	}
 
-    let mut headframe = Frame::new(head, head_pixel, info.width(), info.height());
-    headframe.compute_colors(VideoType::BlackNWhite, (info.width() * info.height()) / (head_pixel as u32).pow(2));
+	let unused_bytes = (self.bytes_per_frame * self.total_frames) as u64 - self.file_size;
 
-    video.write(&headframe.image).unwrap();
+	let hasher = std::mem::replace(&mut self.hasher, Hasher::new(self.info.hash));
+	self.info.checksum = Some(hasher.finalize());
+	self.info.unused_bytes = Some(unused_bytes as u32);
+	self.info.total_frames = Some(self.total_frames);
 
-    let pb = ProgressBar::new(total_frames as u64);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len}, ETA {eta})",
-        )
-        .unwrap(),
-    );
+	let (width, height) = (self.info.width()?, self.info.height()?);
+	write_headframe(self.video.as_mut(), gen_headpage(self.info.clone())?, width, height)?;
+	self.video.finish()?;
 
-    for _ in (0..total_frames).progress_with(pb) {
-	thread::scope(|s| {
-	    s.spawn(|_| {
-		let mut data = Vec::new();
-		for _ in 0..(info.bytes_per_frame()) {
-		    match bytes.next() {
-			Some(b) => data.append(&mut byte_to_bits(&b.unwrap())),
-			_ => data.append(&mut vec![false; 8])
+	Ok(())
+    }
+}
+
+impl Write for EncodeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	let n = buf.len().min(self.bytes_per_frame - self.buffer.len());
+	self.buffer.extend_from_slice(&buf[..n]);
+	self.hasher.update(&buf[..n]);
+	self.file_size += n as u64;
+
+	if self.buffer.len() == self.bytes_per_frame {
+	    match self.write_buffer() {
+		Ok(()) => {},
+		Err(Error::Io(e)) => return Err(e),
+		Err(e) => return Err(io::Error::other(e)),
+	    }
+	}
+
+	Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+	Ok(())
+    }
+}
+
+fn open_video(info: &VideoInfo, output: &str, codec: Option<Codec>, backend: &Backend) -> Result<Box<dyn VideoSink>> {
+    if info.width()? < HEAD_COLUMNS || info.height()? < HEAD_ROWS {
+	return Err(Error::Parameter(format!("the video must be at least {HEAD_COLUMNS}x{HEAD_ROWS} to hold the head frame")));
+    }
+
+    open_sink(output, codec, backend, info.fps()?, info.width()?, info.height()?)
+}
+
+fn write_headframe(video: &mut dyn VideoSink, head: Vec<u8>, width: u32, height: u32) -> Result<()> {
+    let layout = Layout::head(width, height);
+
+    let mut headframe = Frame::new(head, layout);
+    headframe.compute_colors(VideoType::BlackNWhite, false, layout.data_blocks())?;
+
+    video.write(&headframe.image)?;
+    Ok(())
+}
+
+/// renders the data of the frame at `index`, scrambled along with the padding of the last frame
+fn render_frame(mut data: Vec<u8>, index: usize, info: &VideoInfo) -> Result<Frame> {
+    let bytes_per_frame = info.bytes_per_frame()?;
+    data.resize(bytes_per_frame as usize, 0);
+    scramble(&mut data, info.seed, index);
+
+    let mut frame = Frame::new(data, info.layout()?);
+    frame.compute_colors(info.video_type, info.gray_code, bytes_per_frame * 8)?;
+    Ok(frame)
+}
+
+fn write_frame(video: &mut dyn VideoSink, data: Vec<u8>, index: usize, info: &VideoInfo) -> Result<()> {
+    video.write(&render_frame(data, index, info)?.image)?;
+    Ok(())
+}
+
+/// a reader thread cuts the input in frames and hashes it, workers render the frames
+/// concurrently and the calling thread writes them to the video in order
+pub fn create_video<R: Read + Send>(mut info: VideoInfo, mut input: R, output: &str, codec: Option<Codec>, backend: &Backend, progress: Option<&Progress>) -> Result<()> {
+    let total_frames = info.total_frames()?;
+    let bytes_per_frame = info.bytes_per_frame()? as usize;
+    let file_size = info.file_size()?;
+
+    let mut video = open_video(&info, output, codec, backend)?;
+
+    write_headframe(video.as_mut(), gen_headpage(info.clone())?, info.width()?, info.height()?)?;
+
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let head = &info;
+    let hash = info.hash;
+
+    let checksum = thread::scope(|s| {
+	// bounded so that a slow writer stops the reader instead of filling the memory
+	let (chunk_tx, chunk_rx) = channel::bounded::<(usize, Vec<u8>)>(workers * 2);
+	let (frame_tx, frame_rx) = channel::bounded::<(usize, Result<Frame>)>(workers * 2);
+
+	// the reader takes a permit for every chunk and the writer gives it back once the frame
+	// is written, so that a slow frame does not leave the others piling up in `pending`
+	let window = workers * 2;
+	let (permit_tx, permit_rx) = channel::bounded::<()>(window);
+	for _ in 0..window {
+	    permit_tx.send(()).unwrap();
+	}
+
+	let reader = s.spawn(move |_| -> Result<Vec<u8>> {
+	    let mut hasher = Hasher::new(hash);
+	    let mut read = 0;
+
+	    for i in 0..total_frames {
+		// the writer stopped on an error
+		if permit_rx.recv().is_err() {
+		    break;
+		}
+
+		let mut data = Vec::with_capacity(bytes_per_frame);
+		(&mut input).take(bytes_per_frame as u64).read_to_end(&mut data)?;
+		hasher.update(&data);
+
+		// only the last frame can be short, a shorter input would leave the video unreadable
+		read += data.len() as u64;
+		if read < ((i + 1) as u64 * bytes_per_frame as u64).min(file_size) {
+		    return Err(Error::Parameter(format!("the input ended after {read} bytes but {file_size} were expected")));
+		}
+
+		// the writer stopped on an error
+		if chunk_tx.send((i, data)).is_err() {
+		    break;
+		}
+	    }
+
+	    Ok(hasher.finalize())
+	});
+
+	for _ in 0..workers {
+	    let chunk_rx = chunk_rx.clone();
+	    let frame_tx = frame_tx.clone();
+
+	    s.spawn(move |_| {
+		for (i, data) in chunk_rx {
+		    if frame_tx.send((i, render_frame(data, i, head))).is_err() {
+			break;
 		    }
 		}
-		
-		let mut frame = Frame::new(data, info.pixel_size, info.width(), info.height());
-		frame.compute_colors(info.video_type, info.bytes_per_frame() * 8);
-		video.write(&frame.image).unwrap();
 	    });
-	}).unwrap();
-    }
+	}
+	drop(chunk_rx);
+	drop(frame_tx);
+
+	// frames are rendered out of order, they wait here until the previous ones are written
+	let mut pending = BTreeMap::new();
+	let mut next = 0;
+
+	for (i, frame) in frame_rx {
+	    pending.insert(i, frame);
+
+	    while let Some(frame) = pending.remove(&next) {
+		video.write(&frame?.image)?;
+		next += 1;
+		let _ = permit_tx.send(());
+
+		if let Some(callback) = progress {
+		    callback(next, total_frames);
+		}
+	    }
+	}
+
+	reader.join().unwrap()
+    }).unwrap()?;
+
+    info.checksum = Some(checksum);
+    write_headframe(video.as_mut(), gen_headpage(info.clone())?, info.width()?, info.height()?)?;
+    video.finish()?;
+
+    Ok(())
 }
@@ -0,0 +1,69 @@
+use std::fmt;
+use std::io;
+
+/// errors returned by the encoder and the decoder, `OpenCv` only exists with the opencv
+/// feature so matches need a wildcard arm
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// reading the input or writing the output failed
+    Io(io::Error),
+    /// opencv failed to open, read or write a video
+    #[cfg(feature = "opencv")]
+    OpenCv(opencv::Error),
+    /// the video file is malformed or can't be read by its backend
+    Video(String),
+    /// the head frame of the video could not be parsed
+    Header(String),
+    /// the given settings can't be used to build a video
+    Parameter(String),
+    /// the extracted file does not match the checksum stored in the video
+    Integrity {
+	path: String,
+	expected: String,
+	found: String,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    Error::Io(e) => write!(f, "i/o error: {e}"),
+	    #[cfg(feature = "opencv")]
+	    Error::OpenCv(e) => write!(f, "opencv error: {e}"),
+	    Error::Video(msg) => write!(f, "unreadable video: {msg}"),
+	    Error::Header(msg) => write!(f, "invalid head frame: {msg}"),
+	    Error::Parameter(msg) => write!(f, "invalid parameter: {msg}"),
+	    Error::Integrity { path, expected, found } => write!(
+		f,
+		"{path} is corrupted: expected checksum {expected} but got {found}, try encoding with a bigger pixel size"
+	    ),
+	}
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+	match self {
+	    Error::Io(e) => Some(e),
+	    #[cfg(feature = "opencv")]
+	    Error::OpenCv(e) => Some(e),
+	    _ => None,
+	}
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+	Error::Io(e)
+    }
+}
+
+#[cfg(feature = "opencv")]
+impl From<opencv::Error> for Error {
+    fn from(e: opencv::Error) -> Self {
+	Error::OpenCv(e)
+    }
+}
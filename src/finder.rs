@@ -0,0 +1,297 @@
+use crate::Image;
+use crate::layout::Layout;
+
+/// projective mapping from the grid of a layout to the frame it is drawn on
+#[derive(Debug, Clone, Copy)]
+pub struct Homography([f64; 8]);
+
+impl Homography {
+    /// stretches the grid by `sx` and `sy`
+    pub fn scale(sx: f64, sy: f64) -> Self {
+	Self([sx, 0.0, 0.0, 0.0, sy, 0.0, 0.0, 0.0])
+    }
+
+    /// mapping each point of `from` on the point of `to` at the same index,
+    /// none when three of them are aligned
+    pub fn from_points(from: [(f64, f64); 4], to: [(f64, f64); 4]) -> Option<Self> {
+	// x' = (h0 x + h1 y + h2) / (h6 x + h7 y + 1) and y' = (h3 x + h4 y + h5) / (h6 x + h7 y + 1)
+	let mut system = [[0.0; 9]; 8];
+	for (i, ((x, y), (u, v))) in from.into_iter().zip(to).enumerate() {
+	    system[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u, u];
+	    system[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v, v];
+	}
+
+	solve(system).map(Self)
+    }
+
+    pub fn map(&self, x: f64, y: f64) -> (f64, f64) {
+	let h = &self.0;
+	let w = h[6] * x + h[7] * y + 1.0;
+	((h[0] * x + h[1] * y + h[2]) / w, (h[3] * x + h[4] * y + h[5]) / w)
+    }
+}
+
+/// gauss-jordan elimination of a linear system given as its augmented matrix
+fn solve(mut system: [[f64; 9]; 8]) -> Option<[f64; 8]> {
+    for col in 0..8 {
+	let pivot = (col..8).max_by(|a, b| system[*a][col].abs().total_cmp(&system[*b][col].abs()))?;
+	if system[pivot][col].abs() < 1e-9 {
+	    return None;
+	}
+	system.swap(col, pivot);
+
+	let pivot_row = system[col];
+	for (row, equation) in system.iter_mut().enumerate() {
+	    if row == col {
+		continue;
+	    }
+
+	    let factor = equation[col] / pivot_row[col];
+	    for (value, p) in equation.iter_mut().zip(pivot_row).skip(col) {
+		*value -= factor * p;
+	    }
+	}
+    }
+
+    Some(std::array::from_fn(|i| system[i][8] / system[i][i]))
+}
+
+/// maps the grid of `layout` on `frame` from its finder patterns, so that a video which has
+/// been shifted, cropped, padded or slightly skewed by a re-encode can still be read. The grid
+/// is assumed to be stretched over the whole frame when they can not be found
+pub fn locate(frame: &Image, layout: &Layout) -> Homography {
+    let stretched = Homography::scale(
+	frame.width as f64 / layout.width as f64,
+	frame.height as f64 / layout.height as f64,
+    );
+
+    find(frame, layout).unwrap_or(stretched)
+}
+
+fn find(frame: &Image, layout: &Layout) -> Option<Homography> {
+    if !layout.has_room() {
+	return None;
+    }
+
+    let image = Binarized::new(frame);
+    let mut candidates = Vec::new();
+
+    for y in 0..frame.height {
+	scan_row(&image, y, &mut candidates);
+    }
+
+    // the center of a finder crosses several rows, a single match is likely data looking like one
+    candidates.retain(|c| c.count >= 2);
+
+    // the finders are the candidates closest to the corners
+    let corner = |key: fn(&Candidate) -> f64| candidates.iter().min_by(|a, b| key(a).total_cmp(&key(b))).copied();
+    let found = [
+	corner(|c| c.x + c.y)?,
+	corner(|c| c.y - c.x)?,
+	corner(|c| c.x - c.y)?,
+	corner(|c| -c.x - c.y)?,
+    ];
+
+    // each side must be as many blocks long as in the grid, which also rules out
+    // a corner taken twice or a finder taken for another one
+    let centers = layout.finder_centers();
+    for (a, b, horizontal) in [(0, 1, true), (2, 3, true), (0, 2, false), (1, 3, false)] {
+	let (expected, module) = if horizontal {
+	    ((centers[b].0 - centers[a].0) / layout.pixel_width as f64, (found[a].module.0 + found[b].module.0) / 2.0)
+	} else {
+	    ((centers[b].1 - centers[a].1) / layout.pixel_height as f64, (found[a].module.1 + found[b].module.1) / 2.0)
+	};
+	let blocks = (found[b].x - found[a].x).hypot(found[b].y - found[a].y) / module;
+
+	if (blocks - expected).abs() > expected / 5.0 {
+	    return None;
+	}
+    }
+
+    Homography::from_points(centers, found.map(|c| (c.x, c.y)))
+}
+
+/// dark pixels of a frame, split from the light ones by otsu's threshold on their gray level
+struct Binarized {
+    width: u32,
+    height: u32,
+    dark: Vec<bool>,
+}
+
+impl Binarized {
+    fn new(frame: &Image) -> Self {
+	let gray: Vec<u8> = frame.data.chunks_exact(3).map(|p| ((p[0] as u32 + p[1] as u32 + p[2] as u32) / 3) as u8).collect();
+
+	let mut histogram = [0u64; 256];
+	for g in &gray {
+	    histogram[*g as usize] += 1;
+	}
+
+	let threshold = otsu(&histogram);
+
+	Self {
+	    width: frame.width,
+	    height: frame.height,
+	    dark: gray.into_iter().map(|g| g <= threshold).collect(),
+	}
+    }
+
+    fn dark(&self, x: u32, y: u32) -> bool {
+	self.dark[(y * self.width + x) as usize]
+    }
+}
+
+/// gray level splitting the histogram in the two classes with the largest variance between them
+fn otsu(histogram: &[u64; 256]) -> u8 {
+    let total: u64 = histogram.iter().sum();
+    let sum: f64 = histogram.iter().enumerate().map(|(g, n)| g as f64 * *n as f64).sum();
+
+    let (mut dark, mut dark_sum) = (0, 0.0);
+    let (mut best, mut threshold) = (0.0, 127);
+
+    for (g, n) in histogram.iter().enumerate() {
+	dark += n;
+	dark_sum += g as f64 * *n as f64;
+
+	let light = total - dark;
+	if dark == 0 || light == 0 {
+	    continue;
+	}
+
+	let mean_gap = dark_sum / dark as f64 - (sum - dark_sum) / light as f64;
+	let variance = dark as f64 * light as f64 * mean_gap * mean_gap;
+	if variance > best {
+	    (best, threshold) = (variance, g as u8);
+	}
+    }
+
+    threshold
+}
+
+/// a possible finder, averaged over the rows it was seen on
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    x: f64,
+    y: f64,
+    /// width and height of a block of the finder, in frame pixels
+    module: (f64, f64),
+    count: u32,
+}
+
+/// looks for the dark, light, dark, light and dark runs of a finder, sized 1:1:3:1:1, on the
+/// row `y`, then checks that the column going through their center crosses the same runs
+fn scan_row(image: &Binarized, y: u32, candidates: &mut Vec<Candidate>) {
+    // start, length and darkness of every run of the row
+    let mut runs: Vec<(u32, u32, bool)> = Vec::new();
+    for x in 0..image.width {
+	let dark = image.dark(x, y);
+	match runs.last_mut() {
+	    Some(run) if run.2 == dark => run.1 += 1,
+	    _ => runs.push((x, 1, dark)),
+	}
+    }
+
+    for window in runs.windows(5).filter(|w| w[0].2) {
+	if finder_module(std::array::from_fn(|i| window[i].1 as i64)).is_none() {
+	    continue;
+	}
+
+	let x = window[2].0 + window[2].1 / 2;
+	let Some((cy, height)) = cross_check(|i| image.dark(x, i as u32), y as i64, image.height as i64) else {
+	    continue;
+	};
+
+	// centered again on the row going through the center of the column
+	let Some((cx, width)) = cross_check(|i| image.dark(i as u32, cy as u32), x as i64, image.width as i64) else {
+	    continue;
+	};
+
+	let found = candidates.iter_mut().find(|c| (c.x - cx).abs() <= c.module.0 && (c.y - cy).abs() <= c.module.1);
+	match found {
+	    Some(c) => {
+		let average = |old: f64, new: f64| (old * c.count as f64 + new) / (c.count + 1) as f64;
+		(c.x, c.y) = (average(c.x, cx), average(c.y, cy));
+		c.module = (average(c.module.0, width), average(c.module.1, height));
+		c.count += 1;
+	    },
+	    None => candidates.push(Candidate { x: cx, y: cy, module: (width, height), count: 1 }),
+	}
+    }
+}
+
+/// center and block size of the finder runs around the dark pixel at `center` of a line
+/// of `len` pixels, whose darkness is given by `dark`
+fn cross_check(dark: impl Fn(i64) -> bool, center: i64, len: i64) -> Option<(f64, f64)> {
+    if !dark(center) {
+	return None;
+    }
+
+    // first pixel past the run of `is_dark` pixels going from `start` by `step`
+    let run_end = |start: i64, step: i64, is_dark: bool| {
+	let mut i = start;
+	while (0..len).contains(&i) && dark(i) == is_dark {
+	    i += step;
+	}
+	i
+    };
+
+    let (before, after) = (run_end(center, -1, true), run_end(center, 1, true));
+    let (before_light, after_light) = (run_end(before, -1, false), run_end(after, 1, false));
+    let (before_dark, after_dark) = (run_end(before_light, -1, true), run_end(after_light, 1, true));
+
+    let module = finder_module([
+	before_light - before_dark,
+	before - before_light,
+	after - before - 1,
+	after_light - after,
+	after_dark - after_light,
+    ])?;
+
+    Some(((before + 1 + after) as f64 / 2.0, module))
+}
+
+/// size of a block if the runs are sized 1:1:3:1:1, within half a block
+fn finder_module(runs: [i64; 5]) -> Option<f64> {
+    let module = runs.iter().sum::<i64>() as f64 / 7.0;
+    if module < 1.0 {
+	return None;
+    }
+
+    let fits = runs.iter().zip([1.0, 1.0, 3.0, 1.0, 1.0]).all(|(run, blocks)| (*run as f64 - module * blocks).abs() < module * blocks / 2.0);
+    fits.then_some(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close((x, y): (f64, f64), (u, v): (f64, f64)) {
+	assert!((x - u).abs() < 1e-6 && (y - v).abs() < 1e-6, "({x}, {y}) is not ({u}, {v})");
+    }
+
+    #[test]
+    fn the_corners_are_mapped_on_the_given_points() {
+	let from = [(0.0, 0.0), (100.0, 0.0), (0.0, 50.0), (100.0, 50.0)];
+	let to = [(12.0, 7.0), (215.0, 11.0), (9.0, 108.0), (220.0, 104.0)];
+	let homography = Homography::from_points(from, to).unwrap();
+
+	for (f, t) in from.into_iter().zip(to) {
+	    assert_close(homography.map(f.0, f.1), t);
+	}
+    }
+
+    #[test]
+    fn a_scale_is_found_back() {
+	let from = [(4.5, 4.5), (60.5, 4.5), (4.5, 30.5), (60.5, 30.5)];
+	let to = from.map(|(x, y)| (x * 2.0, y * 3.0));
+	let homography = Homography::from_points(from, to).unwrap();
+
+	assert_close(homography.map(17.0, 23.0), Homography::scale(2.0, 3.0).map(17.0, 23.0));
+    }
+
+    #[test]
+    fn aligned_points_have_no_mapping() {
+	let from = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 0.0)];
+	assert!(Homography::from_points(from, from).is_none());
+    }
+}
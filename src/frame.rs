@@ -1,108 +1,126 @@
-use crate::{VideoType, error};
-use opencv::core::{Mat, MatTrait, CV_8UC3};
-use itertools::Itertools;
-
-#[derive(Debug)]
-struct Pixel {
-    x: u32,
-    y: u32,
-    r: u8,
-    g: u8,
-    b: u8
-}
+use crate::{VideoType, Image, Result, get_bit, yuv_to_bgr, from_gray};
+use crate::layout::{Layout, Cell, PADDING};
 
 pub struct Frame {
-    pub image: Mat,
-    pub data: Vec<bool>,
-    pub pixel_size: u32,
-    pub width: u32,
-    pub height: u32
+    pub image: Image,
+    /// bits to draw, packed in bytes
+    pub data: Vec<u8>,
+    pub layout: Layout,
 }
 
 impl Frame {
-    pub fn new(data: Vec<bool>, pixel_size: u8, width: u32, height: u32) -> Self {
-	// assert!(data.len() as u32 <= (width / pixel_size as u32) * (height / pixel_size as u32));
-	unsafe {
-	    Frame {
-		image: Mat::new_rows_cols(height as i32, width as i32, CV_8UC3).unwrap(),
-		data,
-		pixel_size: pixel_size as u32,
-		width,
-		height
-	    }
-	}
+    /// the image has the size of the frame `layout` is mapped on, what the grid does not cover is padding
+    pub fn new(data: Vec<u8>, layout: Layout) -> Self {
+	let mut image = Image::new(layout.frame_width, layout.frame_height);
+	image.data.fill(PADDING);
+
+	Frame { image, data, layout }
     }
 
-    pub fn compute_colors(&mut self, video_type: VideoType, bits_per_page: u32) {
-	assert!(self.data.len() as u32 <= bits_per_page);
-	assert!(self.width % self.pixel_size == 0);
-	assert!(self.height % self.pixel_size == 0);
+    /// `gray_code` maps the symbols of the multi-level video types to gray coded levels
+    pub fn compute_colors(&mut self, video_type: VideoType, gray_code: bool, bits_per_page: u32) -> Result<()> {
+	// the missing bits of the last frame are padding, get_bit reads them as 0
+	assert!(self.data.len() as u32 * 8 <= bits_per_page);
+
+	let block_color = |data: &[u8], idx: usize, chroma: Option<usize>| match video_type {
+	    VideoType::BlackNWhite => encode_black_and_white(data, idx),
+	    VideoType::Color => encode_color(data, idx),
+	    VideoType::Chroma420 => encode_chroma420(data, idx, chroma),
+	    VideoType::GrayScale => encode_levels(data, idx, (1, 3), gray_code),
+	    VideoType::Color8 => encode_levels(data, idx, (3, 3), gray_code),
+	    VideoType::Color16 => encode_levels(data, idx, (3, 4), gray_code),
+	};
 
-	while (self.data.len() as u32) < bits_per_page {
-	    self.data.push(false);
-	}
+	let layout = self.layout;
+	let mut cells = layout.cells();
+
+	// the chroma bits come after the luma bits of every block
+	let squares = matches!(video_type, VideoType::Chroma420).then(|| layout.chroma_squares());
+	let data_blocks = layout.data_blocks() as usize;
+	let row_len = layout.frame_width as usize * 3;
+
+	let image = &mut self.image.data;
+
+	for j in 0..layout.rows() {
+	    let (y0, y1) = layout.row_span(j);
+	    let start = y0 as usize * row_len;
+	    let line = &mut image[start..start + row_len];
+
+	    for (i, _, cell) in cells.by_ref().take(layout.columns() as usize) {
+		let bgr = match cell {
+		    Cell::Finder(true) => [0, 0, 0],
+		    Cell::Finder(false) => [255, 255, 255],
+		    Cell::Calibration(color) => color,
+		    Cell::Data(index) => {
+			let chroma = squares.as_ref().and_then(|s| s.get(i, j)).map(|s| data_blocks + s * 2);
+			block_color(&self.data, index, chroma)
+		    },
+		};
 
-	let mut coords = Vec::new();
-	for j in 0..(self.height / self.pixel_size) {
-	    for i in 0..(self.width / self.pixel_size) {
-		coords.push((i, j))
+		let (x0, x1) = layout.column_span(i);
+		for pixel in line[x0 as usize * 3..x1 as usize * 3].chunks_exact_mut(3) {
+		    pixel.copy_from_slice(&bgr);
+		}
 	    }
-	}
 
-	let pixels: Vec<Pixel> = match video_type {
-	    VideoType::BlackNWhite => encode_black_and_white(&self.data, coords, self.pixel_size),
-	    VideoType::Color => encode_color(&self.data, coords, self.pixel_size),
-	    _ => error("not yet implemented"),
-	};
-
-	for pixel in pixels.iter() {
-	    let bgr = self.image.at_2d_mut::<opencv::core::Vec3b>(pixel.y as i32, pixel.x as i32).unwrap();
-	    bgr[0] = pixel.b;
-	    bgr[1] = pixel.g;
-	    bgr[2] = pixel.r;
+	    // every line of a row of blocks is the same
+	    for y in 1..(y1 - y0) as usize {
+		image.copy_within(start..start + row_len, start + y * row_len);
+	    }
 	}
+
+	Ok(())
     }
 }
 
-fn encode_black_and_white(data: &[bool], coords: Vec<(u32, u32)>, pixel_size: u32) -> Vec<Pixel> {
-    let mut pixels = Vec::new();
-
-    for (idx, &b) in data.iter().enumerate() {
-	let (i, j) = (coords[idx].0, coords[idx].1);
-	
-	for y in (j * pixel_size)..(j * pixel_size + pixel_size) {
-	    for x in (i * pixel_size)..(i * pixel_size + pixel_size) {
-		let pixel = if b {
-		    Pixel {x, y, r: 255, g: 255, b: 255}
-		} else {
-		    Pixel {x, y, r: 0, g: 0, b: 0}
-		};
-
-		pixels.push(pixel);
-	    }
-	}
+/// luma levels of `VideoType::Chroma420`, far enough from the ends for the chroma to stay in range
+const LUMA_LEVELS: [u8; 2] = [64, 192];
+/// distance of the chroma levels of `VideoType::Chroma420` from the neutral 128
+const CHROMA_OFFSET: u8 = 32;
+
+/// bgr color of the block at `idx`
+fn encode_black_and_white(data: &[u8], idx: usize) -> [u8; 3] {
+    if get_bit(data, idx) {
+	[255, 255, 255]
+    } else {
+	[0, 0, 0]
     }
-
-    pixels
 }
 
-fn encode_color(data: &[bool], coords: Vec<(u32, u32)>, pixel_size: u32) -> Vec<Pixel> {
-    let mut pixels = Vec::new();
+/// bgr color of the block at `idx`, each channel holds a bit
+fn encode_color(data: &[u8], idx: usize) -> [u8; 3] {
+    let channel = |bit| if get_bit(data, idx * 3 + bit) { 255 } else { 0 };
+    [channel(2), channel(1), channel(0)]
+}
 
-    for (idx, chunk) in (&data.iter().chunks(3)).into_iter().enumerate() {
-	let (i, j) = (coords[idx].0, coords[idx].1);
-	let bits: Vec<bool> = chunk.copied().collect();
-	
-	for y in (j * pixel_size)..(j * pixel_size + pixel_size) {
-	    for x in (i * pixel_size)..(i * pixel_size + pixel_size) {
-		let r = if bits[0] { 255 } else { 0 };
-		let g = if bits[1] { 255 } else { 0 };
-		let b = if bits[2] { 255 } else { 0 };
+/// bgr color of the block at `idx`, its bit sets the luma and the two bits at `chroma`, shared
+/// with the other blocks of its square, set the chroma. Blocks outside of the squares are neutral
+fn encode_chroma420(data: &[u8], idx: usize, chroma: Option<usize>) -> [u8; 3] {
+    let luma = LUMA_LEVELS[get_bit(data, idx) as usize];
+    let level = |bit| if get_bit(data, bit) { 128 + CHROMA_OFFSET } else { 128 - CHROMA_OFFSET };
 
-		pixels.push(Pixel {x, y, r, g, b});
-	    }
-	}
+    match chroma {
+	Some(c) => yuv_to_bgr([luma, level(c), level(c + 1)]),
+	None => [luma; 3],
     }
+}
 
-    pixels
+/// bgr color of the block at `idx`, each of its `channels` takes `bits` bits drawn as one of
+/// 2^bits evenly spaced levels, a single channel is drawn as gray. With `gray_code`, the
+/// symbols of neighboring levels only differ by one bit, so mistaking a level for the next
+/// one only costs a bit
+fn encode_levels(data: &[u8], idx: usize, (channels, bits): (usize, usize), gray_code: bool) -> [u8; 3] {
+    let max = (1 << bits) - 1;
+
+    let channel = |c: usize| {
+	let start = (idx * channels + c) * bits;
+	let symbol = (start..start + bits).fold(0, |s, bit| s << 1 | get_bit(data, bit) as u32);
+	let level = if gray_code { from_gray(symbol) } else { symbol };
+	(level * 255 / max) as u8
+    };
+
+    match channels {
+	1 => [channel(0); 3],
+	_ => [channel(2), channel(1), channel(0)],
+    }
 }
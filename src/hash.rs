@@ -0,0 +1,83 @@
+use crate::{Error, Result};
+
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+/// algorithm used to check the integrity of the extracted file
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+    /// much faster but not cryptographic, only protects against corruption
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    /// length of the raw digest in bytes
+    pub fn digest_len(&self) -> usize {
+	match self {
+	    HashAlgorithm::Sha256 => 32,
+	    HashAlgorithm::Blake3 => 32,
+	    HashAlgorithm::Xxh3 => 16,
+	}
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+	match self {
+	    HashAlgorithm::Sha256 => 0,
+	    HashAlgorithm::Blake3 => 1,
+	    HashAlgorithm::Xxh3 => 2,
+	}
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self> {
+	match id {
+	    0 => Ok(HashAlgorithm::Sha256),
+	    1 => Ok(HashAlgorithm::Blake3),
+	    2 => Ok(HashAlgorithm::Xxh3),
+	    d => Err(Error::Header(format!("unreconized hash algorithm {d}"))),
+	}
+    }
+}
+
+/// computes the digest of the data as it flows through
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+	match algorithm {
+	    HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+	    HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+	    HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+	}
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+	match self {
+	    Hasher::Sha256(h) => h.update(data),
+	    Hasher::Blake3(h) => {
+		h.update(data);
+	    },
+	    Hasher::Xxh3(h) => h.update(data),
+	}
+    }
+
+    /// raw digest of everything given to `update`
+    pub fn finalize(self) -> Vec<u8> {
+	match self {
+	    Hasher::Sha256(h) => h.finalize().to_vec(),
+	    Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+	    Hasher::Xxh3(h) => h.digest128().to_be_bytes().to_vec(),
+	}
+    }
+}
+
+/// lowercase hexadecimal representation of a digest
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
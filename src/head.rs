@@ -1,84 +1,111 @@
-use crate::{HEAD_LENGHT, VideoInfo, VideoType, error, byte_to_bits, bin_to_dec, dec_to_bin, bin_to_str};
-use std::process;
-
-fn parse_head_iterator<'a, I>(mut iterator: I, total_iter: usize) -> Vec<bool> 
-where
-    I: Iterator<Item = &'a bool>,
-{
-    (0..total_iter).map(|_| {
-	if let Some(b) = iterator.next() {
-	    *b
-	} else {
-	    error("no more values in iterator");
-	}
-    }).collect()
+use crate::{HEAD_LENGHT, VideoInfo, VideoType, HashAlgorithm, Error, Result, bytes_to_str};
+
+/// takes the next `len` bytes of the head
+fn parse_head_field<'a>(head: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if head.len() < len {
+	return Err(Error::Header("no more bytes in the head".to_string()));
+    }
+
+    let (field, rest) = head.split_at(len);
+    *head = rest;
+    Ok(field)
 }
 
-pub fn gen_headpage(mut info: VideoInfo) -> Vec<bool>{
-    let filename = info.filename();
-    let checksum = info.checksum();
+pub fn gen_headpage(mut info: VideoInfo) -> Result<Vec<u8>> {
+    let filename = info.filename()?;
+    // the checksum is only known once the data is read, the trailer frame holds it
+    let mut checksum = info.checksum.clone().unwrap_or_default();
+    assert!(checksum.len() <= 32);
+    checksum.resize(32, 0);
 
-    assert!(filename.len() <= 32);
-    assert!(checksum.len() == 64);
+    if filename.len() > 32 {
+	return Err(Error::Parameter(format!("file name {filename} is longer than 32 bytes, rename the file")));
+    }
 
     let mut out = Vec::new();
 
-    let mut video_type_b = match info.video_type {
-	VideoType::BlackNWhite => dec_to_bin(0u8, 4),
-	VideoType::GrayScale => dec_to_bin(1u8, 4),
-	VideoType::Color => dec_to_bin(2u8, 4),
-	VideoType::Color8 => dec_to_bin(3u8, 4),
-	VideoType::Color16 => dec_to_bin(4u8, 4)
+    let video_type_d: u8 = match info.video_type {
+	VideoType::BlackNWhite => 0,
+	VideoType::GrayScale => 1,
+	VideoType::Color => 2,
+	VideoType::Color8 => 3,
+	VideoType::Color16 => 4,
+	VideoType::Chroma420 => 5,
     };
+    let flags = info.streamed as u8 | (info.gray_code as u8) << 1;
 
-    let mut checksum_b: Vec<bool> = checksum.chars().flat_map(|c| byte_to_bits(&(c as u8))).collect();
-    assert!(checksum_b.len() == 512);
+    let mut filename_b = vec![0u8; 32 - filename.len()];
+    filename_b.extend_from_slice(filename.as_bytes());
 
-    let mut filename_b: Vec<bool> = (0..(256 - filename.len() * 8)).map(|_| false).collect();
+    let unused_bytes = info.unused_bytes()?;
+    let total_frames = info.total_frames()? as u32;
 
-    for c in filename.chars() {
-	filename_b.append(&mut byte_to_bits(&(c as u8)))
+    // the decoder maps the grid of the data frames from this resolution to the one it gets
+    let (width, height) = (info.width()?, info.height()?);
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+	return Err(Error::Parameter(format!("a {width}x{height} video is too large")));
     }
-    assert!(filename_b.len() == 256);
-
-    let mut pixel_size_b = dec_to_bin(info.pixel_size, 8);
-    let mut unused_bytes_b = dec_to_bin(info.unused_bytes(), 32);
-    let mut total_frames_b = dec_to_bin(info.total_frames() as u32, 32);
-
-    out.append(&mut video_type_b);   // 4 bits
-    out.append(&mut checksum_b);     // 512 bits
-    out.append(&mut filename_b);     // 256 bits
-    out.append(&mut pixel_size_b);   // 8 bits
-    out.append(&mut unused_bytes_b); // 32 bits
-    out.append(&mut total_frames_b); // 32 bits
-    // => total of 844 bits
+    let (width, height) = (width as u16, height as u16);
 
-    assert!(out.len() == HEAD_LENGHT as usize);
+    if info.margin > u16::MAX as u32 {
+	return Err(Error::Parameter(format!("a margin of {} pixels is too large", info.margin)));
+    }
+    let margin = info.margin as u16;
+
+    out.push(video_type_d << 4 | flags);                 // 4 + 4 bits
+    out.push(info.hash.id());                           // 8 bits
+    out.extend_from_slice(&checksum);                   // 256 bits
+    out.extend_from_slice(&filename_b);                 // 256 bits
+    out.push(info.pixel_width);                         // 8 bits
+    out.push(info.pixel_height);                        // 8 bits
+    out.extend_from_slice(&unused_bytes.to_be_bytes()); // 32 bits
+    out.extend_from_slice(&total_frames.to_be_bytes()); // 32 bits
+    out.extend_from_slice(&width.to_be_bytes());        // 16 bits
+    out.extend_from_slice(&height.to_be_bytes());       // 16 bits
+    out.extend_from_slice(&margin.to_be_bytes());       // 16 bits
+    out.extend_from_slice(&info.seed.to_be_bytes());    // 32 bits
+    // => total of 688 bits
+
+    assert!(out.len() * 8 == HEAD_LENGHT as usize);
     
-    out
+    Ok(out)
 }
 
-pub fn parse_headpage(head_raw: &[bool]) -> VideoInfo {
-    let head: Vec<bool> = (0..(HEAD_LENGHT as usize)).map(|i| head_raw[i]).collect();
-    let mut head_iter = head.iter();
-
-    let video_type_b = parse_head_iterator(&mut head_iter, 4);
-    let checksum_b = parse_head_iterator(&mut head_iter, 512);
-    let filename_b = parse_head_iterator(&mut head_iter, 256);
-    let pixel_size_b = parse_head_iterator(&mut head_iter, 8);
-    let unused_bytes_b = parse_head_iterator(&mut head_iter, 32);
-    let total_frames_b = parse_head_iterator(&mut head_iter, 32);
-
-    assert!(head_iter.next().is_none());
-
-    let checksum = bin_to_str(&checksum_b);
-    let filename = bin_to_str(&filename_b);
-
-    let video_type_d = bin_to_dec(&video_type_b);
+pub fn parse_headpage(head_raw: &[u8]) -> Result<VideoInfo> {
+    if head_raw.len() * 8 < HEAD_LENGHT as usize {
+	return Err(Error::Header(format!("expected {HEAD_LENGHT} bits but the frame only holds {}", head_raw.len() * 8)));
+    }
 
-    let pixel_size = bin_to_dec(&pixel_size_b);
-    let unused_bytes = bin_to_dec(&unused_bytes_b);
-    let total_frames = bin_to_dec(&total_frames_b);
+    let mut head = &head_raw[..(HEAD_LENGHT as usize / 8)];
+
+    let video_type_b = parse_head_field(&mut head, 1)?[0];
+    let hash_b = parse_head_field(&mut head, 1)?[0];
+    let checksum_b = parse_head_field(&mut head, 32)?;
+    let filename_b = parse_head_field(&mut head, 32)?;
+    let pixel_width = parse_head_field(&mut head, 1)?[0];
+    let pixel_height = parse_head_field(&mut head, 1)?[0];
+    let unused_bytes_b = parse_head_field(&mut head, 4)?;
+    let total_frames_b = parse_head_field(&mut head, 4)?;
+    let width_b = parse_head_field(&mut head, 2)?;
+    let height_b = parse_head_field(&mut head, 2)?;
+    let margin_b = parse_head_field(&mut head, 2)?;
+    let seed_b = parse_head_field(&mut head, 4)?;
+
+    assert!(head.is_empty());
+
+    let hash = HashAlgorithm::from_id(hash_b)?;
+    let checksum = checksum_b[..hash.digest_len()].to_vec();
+    let filename = bytes_to_str(filename_b);
+
+    let video_type_d = video_type_b >> 4;
+    let flags = video_type_b & 0x0f;
+
+    let unused_bytes = u32::from_be_bytes(unused_bytes_b.try_into().unwrap());
+    let total_frames = u32::from_be_bytes(total_frames_b.try_into().unwrap()) as usize;
+    let width = u16::from_be_bytes(width_b.try_into().unwrap()) as u32;
+    let height = u16::from_be_bytes(height_b.try_into().unwrap()) as u32;
+    let margin = u16::from_be_bytes(margin_b.try_into().unwrap()) as u32;
+    let seed = u32::from_be_bytes(seed_b.try_into().unwrap());
 
     let video_type = match video_type_d {
 	0 => VideoType::BlackNWhite,
@@ -86,11 +113,73 @@ pub fn parse_headpage(head_raw: &[bool]) -> VideoInfo {
 	2 => VideoType::Color,
 	3 => VideoType::Color8,
 	4 => VideoType::Color16,
-	_ => {
-	    eprintln!("unreconized video type");
-	    process::exit(1);
-	}
+	5 => VideoType::Chroma420,
+	d => return Err(Error::Header(format!("unreconized video type {d}"))),
     };
 
-    VideoInfo::from_parse(video_type, checksum, filename, pixel_size, unused_bytes, total_frames)
+    if pixel_width == 0 || pixel_height == 0 || width == 0 || height == 0 {
+	return Err(Error::Header("pixel size or resolution is zero, this is probably not a ytstorage video".to_string()));
+    }
+
+    let mut info = VideoInfo::from_parse(video_type, hash, checksum, filename, (pixel_width, pixel_height), unused_bytes, total_frames);
+    info.streamed = flags & 1 == 1;
+    info.gray_code = flags & 2 == 2;
+    info.width = Some(width);
+    info.height = Some(height);
+    info.margin = margin;
+    info.seed = seed;
+
+    // a misread block of the head must not send the decoder past the end of a frame
+    let bytes_per_frame = info.bytes_per_frame().map_err(|e| Error::Header(e.to_string()))?;
+    let fits = if total_frames == 0 { unused_bytes == 0 } else { unused_bytes < bytes_per_frame };
+    if !fits {
+	return Err(Error::Header(format!("{unused_bytes} unused bytes do not fit in {total_frames} frames of {bytes_per_frame} bytes")));
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_head_round_trips() {
+	let mut info = VideoInfo::new(VideoType::Color8, "data.bin", Some(123_456), (3, 4), 24, 640, 360);
+	info.hash = HashAlgorithm::Blake3;
+	info.checksum = Some((0..32).collect());
+	info.margin = 6;
+	info.seed = 0xdead_beef;
+
+	let parsed = parse_headpage(&gen_headpage(info.clone()).unwrap()).unwrap();
+
+	assert!(matches!(parsed.video_type, VideoType::Color8));
+	assert_eq!(parsed.hash, info.hash);
+	assert_eq!(parsed.checksum, info.checksum);
+	assert_eq!(parsed.filename, info.filename);
+	assert_eq!((parsed.pixel_width, parsed.pixel_height), (3, 4));
+	assert_eq!((parsed.width, parsed.height), (Some(640), Some(360)));
+	assert_eq!((parsed.margin, parsed.seed), (6, 0xdead_beef));
+	assert_eq!(parsed.total_frames, Some(info.total_frames().unwrap()));
+	assert_eq!(parsed.unused_bytes, Some(info.unused_bytes().unwrap()));
+	assert!(!parsed.streamed && parsed.gray_code);
+    }
+
+    #[test]
+    fn unused_bytes_must_fit_in_the_last_frame() {
+	let mut info = VideoInfo::new(VideoType::BlackNWhite, "data.bin", Some(1000), (4, 4), 24, 640, 360);
+	info.checksum = Some(vec![0; 32]);
+	let bytes_per_frame = info.bytes_per_frame().unwrap();
+
+	for (unused, total) in [(bytes_per_frame, 2), (1, 0), (u32::MAX, 1)] {
+	    let mut broken = info.clone();
+	    (broken.unused_bytes, broken.total_frames) = (Some(unused), Some(total));
+	    assert!(matches!(parse_headpage(&gen_headpage(broken).unwrap()), Err(Error::Header(_))));
+	}
+    }
+
+    #[test]
+    fn a_short_head_is_rejected() {
+	assert!(matches!(parse_headpage(&[0; 10]), Err(Error::Header(_))));
+    }
 }
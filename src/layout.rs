@@ -0,0 +1,259 @@
+/// rows of blocks at the top of every frame holding calibration patches instead of data
+pub const CALIBRATION_ROWS: u32 = 1;
+
+/// bgr colors of the calibration patches, repeated along the calibration rows
+pub const CALIBRATION_COLORS: [[u8; 3]; 5] = [
+    [0, 0, 0],
+    [255, 255, 255],
+    [0, 0, 255],
+    [0, 255, 0],
+    [255, 0, 0],
+];
+
+/// the head frame is a grid of this many blocks stretched over the whole frame, whatever
+/// its resolution, so that it can be read before knowing how the video was encoded
+pub const HEAD_COLUMNS: u32 = 48;
+pub const HEAD_ROWS: u32 = 27;
+
+/// blocks on each side of the square taken by a finder pattern in every corner of the grid:
+/// the 7 blocks of a qr code finder and a light ring around them that sets it apart from
+/// the data and from whatever surrounds the frame
+pub const FINDER_SIZE: u32 = 9;
+
+/// gray level of the pixels around the grid, in the margin and where the blocks do not
+/// tile the frame exactly
+pub const PADDING: u8 = 128;
+
+/// area of a block in the grid, the ends are excluded
+#[derive(Debug, Clone, Copy)]
+pub struct Rect {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+/// what a block of the grid holds
+#[derive(Debug, Clone, Copy)]
+pub enum Cell {
+    /// part of a finder pattern, dark or light
+    Finder(bool),
+    Calibration([u8; 3]),
+    /// the index of the block among the data blocks
+    Data(usize),
+}
+
+/// squares of 2x2 data blocks, which carry the chroma bits of `VideoType::Chroma420` at the
+/// halved chroma resolution of 4:2:0 videos, the squares touching a finder or a calibration
+/// patch are left out
+pub struct ChromaSquares {
+    /// squares in a row of the grid
+    columns: u32,
+    /// index of every square among the kept ones, row by row
+    index: Vec<Option<usize>>,
+    count: usize,
+}
+
+impl ChromaSquares {
+    /// index of the square holding the block in `column` of `row`, if it is kept
+    pub fn get(&self, column: u32, row: u32) -> Option<usize> {
+	let (x, y) = (column / 2, row / 2);
+	if x >= self.columns {
+	    return None;
+	}
+
+	self.index.get((y * self.columns + x) as usize).copied().flatten()
+    }
+
+    /// number of kept squares
+    pub fn count(&self) -> usize {
+	self.count
+    }
+}
+
+/// grid of blocks on a `width` x `height` frame, read row by row: the finder patterns
+/// sit in the corners, the calibration patches take the first rows and the data blocks take
+/// the rest. The grid starts after the margin and the pixels left on its right and bottom
+/// when the blocks do not tile the frame are padding. The grid is drawn on frames of that
+/// size but can be sampled on a frame of any other size, its blocks are then scaled to it
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    /// size of a block, which does not need to be square
+    pub pixel_width: u32,
+    pub pixel_height: u32,
+    /// pixels left empty on every side of the grid, codecs are the worst on the edges of a frame
+    pub margin: u32,
+    /// size of the frame the grid is mapped on
+    pub frame_width: u32,
+    pub frame_height: u32,
+}
+
+impl Layout {
+    pub fn new(width: u32, height: u32, pixel_width: u32, pixel_height: u32) -> Self {
+	Self { width, height, pixel_width, pixel_height, margin: 0, frame_width: width, frame_height: height }
+    }
+
+    /// the same grid with `margin` empty pixels around it
+    pub fn with_margin(mut self, margin: u32) -> Self {
+	self.margin = margin;
+	self
+    }
+
+    /// grid of the head frame mapped on a `frame_width` x `frame_height` frame
+    pub fn head(frame_width: u32, frame_height: u32) -> Self {
+	Self::new(HEAD_COLUMNS, HEAD_ROWS, 1, 1).scaled_to(frame_width, frame_height)
+    }
+
+    /// the same grid mapped on a frame of another size
+    pub fn scaled_to(mut self, frame_width: u32, frame_height: u32) -> Self {
+	self.frame_width = frame_width;
+	self.frame_height = frame_height;
+	self
+    }
+
+    /// whether every block covers at least one pixel of the frame
+    pub fn fits(&self) -> bool {
+	self.pixel_width as u64 * self.frame_width as u64 >= self.width as u64
+	    && self.pixel_height as u64 * self.frame_height as u64 >= self.height as u64
+    }
+
+    pub fn columns(&self) -> u32 {
+	self.width.saturating_sub(2 * self.margin) / self.pixel_width
+    }
+
+    pub fn rows(&self) -> u32 {
+	self.height.saturating_sub(2 * self.margin) / self.pixel_height
+    }
+
+    /// whether the finder patterns fit in the grid without touching each other
+    pub fn has_room(&self) -> bool {
+	self.columns() > 2 * FINDER_SIZE && self.rows() > 2 * FINDER_SIZE + CALIBRATION_ROWS
+    }
+
+    /// number of blocks holding data
+    pub fn data_blocks(&self) -> u32 {
+	if !self.has_room() {
+	    return 0;
+	}
+
+	let (columns, rows) = (self.columns(), self.rows());
+	let calibration = CALIBRATION_ROWS * (columns - 2 * FINDER_SIZE);
+
+	columns * rows - 4 * FINDER_SIZE.pow(2) - calibration
+    }
+
+    /// area of the block in `column` of `row`
+    pub fn block(&self, column: u32, row: u32) -> Rect {
+	let (width, height, margin) = (self.pixel_width, self.pixel_height, self.margin);
+	Rect {
+	    x0: margin + column * width,
+	    y0: margin + row * height,
+	    x1: margin + (column + 1) * width,
+	    y1: margin + (row + 1) * height,
+	}
+    }
+
+    /// first and last excluded frame columns of the blocks in `column`
+    pub fn column_span(&self, column: u32) -> (u32, u32) {
+	let block = self.block(column, 0);
+	let scale = |x: u32| (x as u64 * self.frame_width as u64 / self.width as u64) as u32;
+	(scale(block.x0), scale(block.x1))
+    }
+
+    /// first and last excluded frame rows of the blocks in `row`
+    pub fn row_span(&self, row: u32) -> (u32, u32) {
+	let block = self.block(0, row);
+	let scale = |y: u32| (y as u64 * self.frame_height as u64 / self.height as u64) as u32;
+	(scale(block.y0), scale(block.y1))
+    }
+
+    /// whether the block in `column` of `row` is dark, if it belongs to a finder pattern
+    fn finder_module(&self, column: u32, row: u32) -> Option<bool> {
+	let (columns, rows) = (self.columns(), self.rows());
+
+	let x = if column < FINDER_SIZE { column } else { column.checked_sub(columns - FINDER_SIZE)? };
+	let y = if row < FINDER_SIZE { row } else { row.checked_sub(rows - FINDER_SIZE)? };
+
+	// rings around the center: light border, dark ring, light ring and dark center
+	let center = FINDER_SIZE / 2;
+	let ring = x.abs_diff(center).max(y.abs_diff(center));
+	Some(ring == 3 || ring <= 1)
+    }
+
+    /// whether the block in `column` of `row` holds data
+    fn is_data(&self, column: u32, row: u32) -> bool {
+	row >= CALIBRATION_ROWS && self.finder_module(column, row).is_none()
+    }
+
+    /// the squares of 2x2 blocks only holding data, aligned on the even columns and rows
+    pub fn chroma_squares(&self) -> ChromaSquares {
+	let (columns, rows) = (self.columns() / 2, self.rows() / 2);
+	let mut count = 0;
+
+	let index = (0..columns * rows).map(|i| {
+	    let (column, row) = (i % columns * 2, i / columns * 2);
+	    let data = [(0, 0), (1, 0), (0, 1), (1, 1)].iter().all(|(x, y)| self.is_data(column + x, row + y));
+
+	    data.then(|| {
+		count += 1;
+		count - 1
+	    })
+	}).collect();
+
+	ChromaSquares { columns, index, count }
+    }
+
+    /// every block of the grid row by row, with its column, its row and what it holds
+    pub fn cells(&self) -> impl Iterator<Item = (u32, u32, Cell)> + '_ {
+	let columns = self.columns();
+	let (mut calibration, mut data) = (0, 0);
+
+	(0..self.rows() * columns).map(move |i| {
+	    let (column, row) = (i % columns, i / columns);
+
+	    let cell = match self.finder_module(column, row) {
+		Some(dark) => Cell::Finder(dark),
+		None if row < CALIBRATION_ROWS => {
+		    calibration += 1;
+		    Cell::Calibration(CALIBRATION_COLORS[(calibration - 1) % CALIBRATION_COLORS.len()])
+		},
+		None => {
+		    data += 1;
+		    Cell::Data(data - 1)
+		},
+	    };
+
+	    (column, row, cell)
+	})
+    }
+
+    /// area of every data block, in order
+    pub fn data_cells(&self) -> impl Iterator<Item = Rect> + '_ {
+	self.cells().filter_map(|(column, row, cell)| match cell {
+	    Cell::Data(_) => Some(self.block(column, row)),
+	    _ => None,
+	})
+    }
+
+    /// area and color of every calibration patch
+    pub fn calibration_patches(&self) -> impl Iterator<Item = (Rect, [u8; 3])> + '_ {
+	// they all sit in the first rows
+	self.cells().take((CALIBRATION_ROWS * self.columns()) as usize).filter_map(|(column, row, cell)| match cell {
+	    Cell::Calibration(color) => Some((self.block(column, row), color)),
+	    _ => None,
+	})
+    }
+
+    /// centers of the top left, top right, bottom left and bottom right finder patterns
+    pub fn finder_centers(&self) -> [(f64, f64); 4] {
+	let (width, height, margin) = (self.pixel_width as f64, self.pixel_height as f64, self.margin as f64);
+	let half = FINDER_SIZE as f64 / 2.0;
+	let (left, top) = (margin + half * width, margin + half * height);
+	let right = margin + (self.columns() as f64 - half) * width;
+	let bottom = margin + (self.rows() as f64 - half) * height;
+
+	[(left, top), (right, top), (left, bottom), (right, bottom)]
+    }
+}
@@ -0,0 +1,197 @@
+mod convert;
+mod encode;
+mod decode;
+mod frame;
+mod head;
+mod error;
+mod hash;
+mod codec;
+mod backend;
+mod sample;
+mod layout;
+mod finder;
+mod scramble;
+
+use crate::convert::{BitBuffer, get_bit, bytes_to_str, bgr_to_yuv, yuv_to_bgr, clamp, to_gray, from_gray};
+use crate::head::{gen_headpage, parse_headpage};
+use crate::frame::Frame;
+use crate::hash::{Hasher, to_hex};
+use crate::sample::{Sampler, Thresholds};
+use crate::layout::Layout;
+use crate::finder::{Homography, locate};
+use crate::scramble::{scramble, random_seed};
+
+pub use crate::encode::{Encoder, EncodeWriter};
+pub use crate::decode::{Decoder, DecodeReader, Extracted, SoftFrame};
+pub use crate::error::{Error, Result};
+pub use crate::hash::HashAlgorithm;
+pub use crate::codec::{Codec, Container};
+pub use crate::backend::{Image, VideoSink, VideoSource, Backend, FfmpegOptions, open_sink, open_source};
+
+const HEAD_LENGHT: u32 = 688;
+
+/// callback receiving the number of processed frames and the total number of frames,
+/// which is 0 when it is not known yet
+pub type Progress = Box<dyn Fn(usize, usize) + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct VideoInfo {
+    video_type: VideoType,
+    hash: HashAlgorithm,
+    /// raw digest of the file
+    checksum: Option<Vec<u8>>,
+    filename: Option<String>,
+    /// size of the blocks of the data frames
+    pixel_width: u8,
+    pixel_height: u8,
+    /// pixels left empty around the grid of the data frames
+    margin: u32,
+    /// seed of the scrambler the data frames go through
+    seed: u32,
+    unused_bytes: Option<u32>,
+    total_frames: Option<usize>,
+    file_size: Option<u64>,
+    fps: Option<u32>,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// size and checksum are unknown when the head frame is written, they are stored in a trailer frame
+    streamed: bool,
+    /// the levels of the multi-level video types are gray coded
+    gray_code: bool,
+}
+
+impl VideoInfo {
+    /// without a file size, the video is streamed
+    fn new(video_type: VideoType, filename: &str, file_size: Option<u64>, (pixel_width, pixel_height): (u8, u8), fps: u32, width: u32, height: u32) -> Self {
+	Self {
+	    video_type,
+	    hash: HashAlgorithm::default(),
+	    checksum: None,
+	    filename: Some(filename.to_string()),
+	    pixel_width,
+	    pixel_height,
+	    margin: 0,
+	    seed: 0,
+	    unused_bytes: None,
+	    total_frames: None,
+	    file_size,
+	    fps: Some(fps),
+	    width: Some(width),
+	    height: Some(height),
+	    streamed: file_size.is_none(),
+	    gray_code: true,
+	}
+    }
+
+    fn from_parse(video_type: VideoType, hash: HashAlgorithm, checksum: Vec<u8>, filename: String, (pixel_width, pixel_height): (u8, u8), unused_bytes: u32, total_frames: usize) -> Self {
+	Self {
+	    video_type,
+	    hash,
+	    checksum: Some(checksum),
+	    filename: Some(filename),
+	    pixel_width,
+	    pixel_height,
+	    margin: 0,
+	    seed: 0,
+	    unused_bytes: Some(unused_bytes),
+	    total_frames: Some(total_frames),
+	    file_size: None,
+	    fps: None,
+	    width: None,
+	    height: None,
+	    streamed: false,
+	    gray_code: false,
+	}
+    }
+
+    /// grid of the data frames
+    fn layout(&self) -> Result<Layout> {
+	Ok(Layout::new(self.width()?, self.height()?, self.pixel_width as u32, self.pixel_height as u32).with_margin(self.margin))
+    }
+
+    fn bytes_per_frame(&self) -> Result<u32> {
+	let bytes = self.video_type.bits_per_frame(&self.layout()?) / 8;
+
+	if bytes == 0 {
+	    return Err(Error::Parameter(format!(
+		"{}x{} blocks with a margin of {} leave no room for data on a frame, try smaller ones",
+		self.pixel_width, self.pixel_height, self.margin
+	    )));
+	}
+
+	Ok(bytes)
+    }
+
+    fn filename(&self) -> Result<String> {
+	self.filename.clone().ok_or_else(|| Error::Parameter("the name of the file is unknown".to_string()))
+    }
+
+    fn unused_bytes(&mut self) -> Result<u32> {
+	match self.unused_bytes {
+	    Some(s) => Ok(s),
+	    None => {
+		let unused_bytes = (self.bytes_per_frame()? as u64 * self.total_frames()? as u64 - self.file_size()?) as u32;
+		self.unused_bytes = Some(unused_bytes);
+		Ok(unused_bytes)
+	    },
+	}
+    }
+
+    fn total_frames(&mut self) -> Result<usize> {
+	match self.total_frames {
+	    Some(s) => Ok(s),
+	    None => {
+		let total_frames = (self.file_size()? as f64 / self.bytes_per_frame()? as f64).ceil() as usize;
+		self.total_frames = Some(total_frames);
+		Ok(total_frames)
+	    },
+	}
+    }
+
+    fn file_size(&self) -> Result<u64> {
+	self.file_size.ok_or_else(|| Error::Parameter("the size of the file is unknown".to_string()))
+    }
+
+    fn fps(&self) -> Result<u32> {
+	self.fps.ok_or_else(|| Error::Parameter("you need to specify a fps parameter to create a video".to_string()))
+    }
+
+    fn width(&self) -> Result<u32> {
+	self.width.ok_or_else(|| Error::Parameter("you need to specify a width parameter to create a video".to_string()))
+    }
+
+    fn height(&self) -> Result<u32> {
+	self.height.ok_or_else(|| Error::Parameter("you need to specify a height parameter to create a video".to_string()))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum VideoType {
+    BlackNWhite,
+    /// 8 gray levels
+    GrayScale,
+    Color,
+    /// 8 levels per channel
+    Color8,
+    /// 16 levels per channel
+    Color16,
+    /// luma carries a bit per block and chroma two bits per square of 2x2 blocks, so that
+    /// the colors survive the halved chroma resolution of 4:2:0 videos
+    Chroma420,
+}
+
+impl VideoType {
+    /// bits held by a data frame of `layout`
+    fn bits_per_frame(&self, layout: &Layout) -> u32 {
+	let blocks = layout.data_blocks();
+
+	match self {
+	    VideoType::BlackNWhite => blocks,
+	    VideoType::GrayScale => blocks * 3,
+	    VideoType::Color => blocks * 3,
+	    VideoType::Color8 => blocks * 9,
+	    VideoType::Color16 => blocks * 12,
+	    VideoType::Chroma420 => blocks + layout.chroma_squares().count() as u32 * 2,
+	}
+    }
+}
@@ -1,26 +1,15 @@
-mod convert;
-mod encode;
-mod decode;
-mod frame;
-mod head;
-
-use crate::convert::{byte_to_bits, bin_to_dec, dec_to_bin, bin_to_str, pages_to_bytes};
-use crate::head::{gen_headpage, parse_headpage};
-use crate::encode::create_video;
-use crate::decode::decode_video;
-use crate::frame::Frame;
-
-use std::fs::File;
-use std::path::Path;
+use ytstorage::{Encoder, Decoder, DecodeReader, VideoType, HashAlgorithm, Codec, Backend, FfmpegOptions};
+
 use clap::Parser;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io;
+use std::path::Path;
 use std::process;
 
-const HEAD_LENGHT: u32 = 844;
-
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    /// input file path
+    /// input file path, - reads the data to encode from stdin
     #[arg(short, long)]
     file: String,
 
@@ -36,11 +25,31 @@ struct Args {
     #[arg(long, default_value_t = 10)]
     pixel_size: u8,
 
+    /// width of the blocks, overrides pixel_size
+    #[arg(long)]
+    pixel_width: Option<u8>,
+
+    /// height of the blocks, overrides pixel_size
+    #[arg(long)]
+    pixel_height: Option<u8>,
+
+    /// pixels left empty around the data, codecs damage the edges of a frame the most
+    #[arg(long, default_value_t = 0)]
+    margin: u32,
+
+    /// seed of the scrambler making the frames look like noise, random by default
+    #[arg(long)]
+    seed: Option<u32>,
+
+    /// map the levels of grayscale, color8 and color16 in binary order instead of gray code
+    #[arg(long, default_value_t = false)]
+    binary_levels: bool,
+
     /// video file fps
     #[arg(long, default_value_t = 24)]
     fps: u32,
 
-    /// output path (only when compressing)
+    /// output path, when extracting it defaults to the stored file name and - writes to stdout
     #[arg(short, long, default_value = None)]
     output: Option<String>,
 
@@ -48,198 +57,38 @@ struct Args {
     #[arg(short, long, default_value_t = false)]
     extract: bool,
 
-    /// black, grayscale, color, color8 or color16
+    /// black, grayscale, color, color8, color16 or chroma420 (for 4:2:0 codecs)
     #[arg(long, default_value = "black")]
-    video_type: String
-}
+    video_type: String,
 
-#[derive(Debug, Clone)]
-pub struct VideoInfo {
-    video_type: VideoType,
-    checksum: Option<String>,
-    filename: Option<String>,
-    path: Option<String>,
-    pixel_size: u8,
-    unused_bytes: Option<u32>,
-    total_frames: Option<usize>,
-    file_size: Option<u64>,
-    fps: Option<u32>,
-    width: Option<u32>,
-    height: Option<u32>,
-}
+    /// checksum algorithm: sha256, blake3 or xxh3
+    #[arg(long, default_value = "sha256")]
+    hash: String,
 
-impl VideoInfo {
-    fn new(video_type: VideoType, path: &str, pixel_size: u8, fps: u32, width: u32, height: u32) -> Self {
-	Self {
-	    video_type,
-	    checksum: None,
-	    filename: None,
-	    path: Some(path.to_string()),
-	    pixel_size,
-	    unused_bytes: None,
-	    total_frames: None,
-	    file_size: None,
-	    fps: Some(fps),
-	    width: Some(width),
-	    height: Some(height),
-	}
-    }
-
-    fn from_parse(video_type: VideoType, checksum: String, filename: String, pixel_size: u8, unused_bytes: u32, total_frames: usize) -> Self {
-	Self {
-	    video_type,
-	    checksum: Some(checksum),
-	    filename: Some(filename),
-	    path: None,
-	    pixel_size,
-	    unused_bytes: Some(unused_bytes),
-	    total_frames: Some(total_frames),
-	    file_size: None,
-	    fps: None,
-	    width: None,
-	    height: None
-	}
-    }
+    /// h264, vp9, av1, mjpeg or ffv1 (lossless, mkv only), the container follows the output extension,
+    /// h264 by default and refused for .y4m and image outputs
+    #[arg(long, default_value = None)]
+    codec: Option<String>,
 
-    fn total_pixels(&self) -> u32 {
-	if self.width.is_none() || self.height.is_none() {
-	    error("can't get total pixels without a width and a height");
-	}
+    /// opencv or ffmpeg, which reads and writes compressed videos
+    #[arg(long, default_value = None)]
+    backend: Option<String>,
 
-	self.width.unwrap() * self.height.unwrap()
-    }
+    /// ffmpeg constant rate factor, lower keeps more details
+    #[arg(long, default_value = None)]
+    crf: Option<u8>,
 
-    fn bytes_per_frame(&self) -> u32 {
-	match self.video_type {
-	    VideoType::BlackNWhite => get_bytes_per_frame(self.total_pixels(), self.pixel_size as u32, (1, 8)),
-	    VideoType::GrayScale => get_bytes_per_frame(self.total_pixels(), self.pixel_size as u32, (3, 8)),
-	    VideoType::Color => get_bytes_per_frame(self.total_pixels(), self.pixel_size as u32, (3, 8)),
-	    VideoType::Color8 => get_bytes_per_frame(self.total_pixels(), self.pixel_size as u32, (1, 2)),
-	    VideoType::Color16 => get_bytes_per_frame(self.total_pixels(), self.pixel_size as u32, (3, 2)),
-	}
-    }
+    /// ffmpeg encoder preset, like slow for h264
+    #[arg(long, default_value = None)]
+    preset: Option<String>,
 
-    fn load_file(&self) -> File {
-	let path_str = match &self.path {
-	    Some(p) => p,
-	    None => error("need a path to load the file")
-	};
-
-	let path  = Path::new(&path_str);
-	if !path.exists() {
-	    error("File does not exists");
-	}
-
-	File::open(path).expect("Error opening file")
-    }
-
-    fn checksum(&mut self) -> String {
-	match &self.checksum {
-	    Some(s) => s.clone(),
-	    None => {
-		let path_str = match &self.path {
-		    Some(p) => p,
-		    None => error("cannot get checksum without a path")
-		};
-
-		let path = Path::new(&path_str);
-		let checksum = match sha256::try_digest(path) {
-		    Ok(c) => c,
-		    Err(_) => error("cannot get checksum of the file")
-		};
-
-		self.checksum = Some(checksum.clone());
-		checksum
-	    },
-	}
-    }
-
-    fn filename(&mut self) -> String {
-	match &self.filename {
-	    Some(s) => s.clone(),
-	    None => {
-		let path_str = match &self.path {
-		    Some(p) => p,
-		    None => error("cannot get filename without a path")
-		};
-
-		let path = Path::new(&path_str);
-		let filename = match path.file_name() {
-		    Some(f) => match f.to_str() {
-			Some(s) => s.to_string(),
-			None => error("there may be weird symbols in the file name")
-		    },
-		    None => error("failed to get file name"),
-		};
-
-		self.filename = Some(filename.clone());
-		filename
-	    },
-	}
-    }
-
-    fn unused_bytes(&mut self) -> u32 {
-	match self.unused_bytes {
-	    Some(s) => s,
-	    None => {
-		let unused_bytes = self.bytes_per_frame() * self.total_frames() as u32 - self.file_size() as u32;
-		self.unused_bytes = Some(unused_bytes);
-		unused_bytes
-	    },
-	}
-    }
-
-    fn total_frames(&mut self) -> usize {
-	match self.total_frames {
-	    Some(s) => s,
-	    None => {
-		let total_frames = (self.file_size() as f64 / self.bytes_per_frame() as f64).ceil() as usize;
-		self.total_frames = Some(total_frames);
-		total_frames
-	    },
-	}
-    }
-
-    fn file_size(&mut self) -> u64 {
-	match self.file_size {
-	    Some(s) => s,
-	    None => {
-		let file_size = get_file_size(self.load_file());
-		self.file_size = Some(file_size);
-		file_size
-	    },
-	}
-    }
+    /// ffmpeg pixel format of the video, like yuv444p
+    #[arg(long, default_value = None)]
+    pix_fmt: Option<String>,
 
-    fn fps(&self) -> u32 {
-	match self.fps {
-	    Some(f) => f,
-	    None => error("you need to specify a fps parameter to create a video")
-	}
-    }
-
-    fn width(&self) -> u32 {
-	match self.width {
-	    Some(f) => f,
-	    None => error("you need to specify a width parameter to create a video")
-	}
-    }
-
-    fn height(&self) -> u32 {
-	match self.height {
-	    Some(f) => f,
-	    None => error("you need to specify a height parameter to create a video")
-	}
-    }
-}
-
-#[derive(Debug, Copy, Clone)]
-pub enum VideoType {
-    BlackNWhite,
-    GrayScale,
-    Color,
-    Color8,
-    Color16
+    /// ffmpeg maximum number of frames between two keyframes
+    #[arg(long, default_value = None)]
+    keyint: Option<u32>
 }
 
 fn error(msg: &str) -> ! {
@@ -247,73 +96,131 @@ fn error(msg: &str) -> ! {
     process::exit(1)
 }
 
-fn get_file_size(file: File) -> u64 {
-    let meta = file.metadata();
+fn progress_bar() -> Result<ProgressBar, Box<dyn std::error::Error>> {
+    let pb = ProgressBar::new(0);
+    pb.set_style(
+	ProgressStyle::with_template(
+	    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len}, ETA {eta})",
+	)?,
+    );
 
-    match meta {
-	Ok(m) => m.len(),
-	Err(_) => error("failed to get file size")
-    }
-}
-
-fn get_bytes_per_frame(total_pixels: u32, pixel_size: u32, byte_per_pixel: (u32, u32)) -> u32 {
-    let numerator = byte_per_pixel.0;
-    let denominator = byte_per_pixel.1;
-    
-    let pixels_per_frame = if (total_pixels * numerator) % pixel_size.pow(2) == 0 {
-	(total_pixels * numerator) / pixel_size.pow(2)
-    } else {
-	error("width, height, pixel_size and video_type are uncompatible");
-    };
-    if pixels_per_frame % denominator == 0 {
-	pixels_per_frame / denominator
-    } else {
-	error("width, height, pixel_size and video_type are uncompatible");
-    }
-}
-
-fn gcd(mut a: u32, mut b: u32) -> u32 {
-    while b != 0 {
-        let remainder = a % b;
-        a = b;
-        b = remainder;
-    }
-    a
+    Ok(pb)
 }
 
-fn max_pixel_size(bytes_on_frame: u32, width: u32, height: u32) -> u8 {
-    let mut pixel_size = gcd(width, height);
-    while width % pixel_size != 0
-	|| height % pixel_size != 0
-	|| (width * height) / pixel_size.pow(2) <= bytes_on_frame
-    {
-	pixel_size -= 1;
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+	error(&e.to_string());
     }
-
-    pixel_size as u8
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
     let video_type = match args.video_type.as_str() {
 	"black" => VideoType::BlackNWhite,
 	"grayscale" => VideoType::GrayScale,
 	"color" => VideoType::Color,
 	"color8" => VideoType::Color8,
 	"color16" => VideoType::Color16,
+	"chroma420" => VideoType::Chroma420,
 	_ => error("unreconized video type parameter"),
     };
 
-    let info = VideoInfo::new(video_type, &args.file, args.pixel_size, args.fps, args.width, args.height);
-    
+    let hash = match args.hash.as_str() {
+	"sha256" => HashAlgorithm::Sha256,
+	"blake3" => HashAlgorithm::Blake3,
+	"xxh3" => HashAlgorithm::Xxh3,
+	_ => error("unreconized hash parameter"),
+    };
+
+    let codec = args.codec.as_deref().map(|codec| match codec {
+	"h264" => Codec::H264,
+	"vp9" => Codec::Vp9,
+	"av1" => Codec::Av1,
+	"mjpeg" => Codec::Mjpeg,
+	"ffv1" => Codec::Ffv1,
+	_ => error("unreconized codec parameter"),
+    });
+
+    let options = FfmpegOptions {
+	crf: args.crf,
+	preset: args.preset.clone(),
+	pix_fmt: args.pix_fmt.clone(),
+	keyint: args.keyint,
+    };
+
+    // the encoder settings only exist for ffmpeg, asking for them picks it
+    let tuned = options.crf.is_some() || options.preset.is_some() || options.pix_fmt.is_some() || options.keyint.is_some();
+    let backend = match args.backend.as_deref() {
+	Some("opencv") if tuned => error("--crf, --preset, --pix-fmt and --keyint need the ffmpeg backend"),
+	Some("opencv") => Backend::OpenCv,
+	Some("ffmpeg") => Backend::Ffmpeg(options),
+	None if tuned => Backend::Ffmpeg(options),
+	None => Backend::default(),
+	_ => error("unreconized backend parameter"),
+    };
+
+    let pb = progress_bar()?;
+    let bar = pb.clone();
+    let progress = move |pos: usize, len: usize| {
+	if len > 0 {
+	    bar.set_length(len as u64);
+	}
+	bar.set_position(pos as u64);
+    };
+
     if !args.extract {
-	if let Some(output) = args.output {
-	    create_video(info, &output);
+	let output = match args.output {
+	    Some(o) => o,
+	    None => error("Please specify a name for the output file"),
+	};
+
+	let encoder = Encoder::new()
+	    .video_type(video_type)
+	    .hash(hash)
+	    .backend(backend)
+	    .pixel_width(args.pixel_width.unwrap_or(args.pixel_size))
+	    .pixel_height(args.pixel_height.unwrap_or(args.pixel_size))
+	    .margin(args.margin)
+	    .gray_code(!args.binary_levels)
+	    .fps(args.fps)
+	    .resolution(args.width, args.height);
+
+	let encoder = match args.seed {
+	    Some(seed) => encoder.seed(seed),
+	    None => encoder,
+	};
+
+	let encoder = match codec {
+	    Some(codec) => encoder.codec(codec),
+	    None => encoder,
+	};
+
+	if args.file == "-" {
+	    // the data has no name, the video's one is stored instead
+	    let filename = Path::new(&output)
+		.file_stem()
+		.and_then(|s| s.to_str())
+		.unwrap_or("stdin");
+
+	    let mut writer = encoder.writer(filename, &output)?;
+	    io::copy(&mut io::stdin().lock(), &mut writer)?;
+	    writer.finish()?;
 	} else {
-	    error("Please specify a name for the output file");
+	    encoder.on_progress(progress).encode(&args.file, &output)?;
+	    pb.finish();
 	}
+    } else if args.output.as_deref() == Some("-") {
+	let mut reader = DecodeReader::open(&args.file, &backend)?;
+	io::copy(&mut reader, &mut io::stdout().lock())?;
     } else {
-	decode_video(&args.file)?;
+	let mut decoder = Decoder::new().backend(backend).on_progress(progress);
+	if let Some(output) = &args.output {
+	    decoder = decoder.output(output);
+	}
+
+	let extracted = decoder.decode(&args.file)?;
+	pb.finish();
+
+	println!("{} is intact", extracted.path);
     }
 
     Ok(())
@@ -0,0 +1,247 @@
+use crate::{Image, bgr_to_yuv};
+use crate::finder::Homography;
+use crate::layout::{Layout, Rect};
+
+/// most points read along each side of a block, enough to drop the outliers
+const MAX_POINTS: usize = 6;
+
+/// reads the color of a block from its inner region, compression bleeds the most on the edges
+/// of a block and ringing leaves a few outliers inside, so the edges are skipped and the
+/// darkest and brightest quarters of every channel are dropped before averaging
+#[derive(Default)]
+pub struct Sampler {
+    /// values of one channel inside the block, reused between blocks
+    values: [Vec<u8>; 3],
+}
+
+impl Sampler {
+    pub fn new() -> Self {
+	Self::default()
+    }
+
+    /// bgr color of the frame pixels covered by `block`, once mapped on the frame by `mapping`
+    pub fn block(&mut self, frame: &Image, mapping: &Homography, block: Rect) -> [u8; 3] {
+	let (x0, x1) = inner(block.x0, block.x1);
+	let (y0, y1) = inner(block.y0, block.y1);
+
+	// about one point per frame pixel of the inner region
+	let (left, top) = mapping.map(x0, y0);
+	let (right, bottom) = mapping.map(x1, y1);
+	let columns = ((right - left).abs().round() as usize).clamp(1, MAX_POINTS);
+	let rows = ((bottom - top).abs().round() as usize).clamp(1, MAX_POINTS);
+
+	for values in &mut self.values {
+	    values.clear();
+	}
+
+	for j in 0..rows {
+	    let y = y0 + (j as f64 + 0.5) * (y1 - y0) / rows as f64;
+
+	    for i in 0..columns {
+		let x = x0 + (i as f64 + 0.5) * (x1 - x0) / columns as f64;
+
+		let (fx, fy) = mapping.map(x, y);
+		let fx = (fx.max(0.0) as u32).min(frame.width - 1);
+		let fy = (fy.max(0.0) as u32).min(frame.height - 1);
+
+		for (values, c) in self.values.iter_mut().zip(frame.pixel(fx, fy)) {
+		    values.push(c);
+		}
+	    }
+	}
+
+	let mut bgr = [0; 3];
+	for (c, values) in bgr.iter_mut().zip(&mut self.values) {
+	    *c = trimmed_mean(values);
+	}
+
+	bgr
+    }
+}
+
+/// a quarter of the span on each side
+fn inner(start: u32, end: u32) -> (f64, f64) {
+    let margin = (end - start) as f64 / 4.0;
+    (start as f64 + margin, end as f64 - margin)
+}
+
+fn trimmed_mean(values: &mut [u8]) -> u8 {
+    values.sort_unstable();
+
+    let trim = values.len() / 4;
+    let kept = &values[trim..values.len() - trim];
+
+    (kept.iter().map(|v| *v as u32).sum::<u32>() / kept.len() as u32) as u8
+}
+
+/// levels splitting the blocks that are off from the ones that are on, measured on the
+/// calibration patches of each frame since the brightness and the color range of a video
+/// can be shifted by its re-encodes
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// per channel, for the color modes
+    pub bgr: [u8; 3],
+    /// on the mean of the channels, for black and white
+    pub gray: u8,
+    /// on the luma and neutral chroma, for `VideoType::Chroma420`
+    pub yuv: [u8; 3],
+    /// off and on levels per channel, for the multi-level color modes
+    pub ranges: [(u8, u8); 3],
+    /// black and white levels of the mean of the channels, for grayscale
+    pub gray_range: (u8, u8),
+}
+
+impl Thresholds {
+    pub fn measure(frame: &Image, layout: &Layout, mapping: &Homography, sampler: &mut Sampler) -> Self {
+	// sum and count of every channel when it is on and when it is off
+	let mut on = [(0, 0); 3];
+	let mut off = [(0, 0); 3];
+	let (mut white, mut black) = ((0, 0), (0, 0));
+	// luma of the white and black patches, and their chroma which is neutral
+	let (mut white_luma, mut black_luma) = ((0, 0), (0, 0));
+	let mut chroma = ([0, 0], 0);
+
+	for (block, color) in layout.calibration_patches() {
+	    let bgr = sampler.block(frame, mapping, block);
+
+	    for c in 0..3 {
+		let level = if color[c] == 255 { &mut on[c] } else { &mut off[c] };
+		*level = (level.0 + bgr[c] as u32, level.1 + 1);
+	    }
+
+	    let gray = bgr.iter().map(|c| *c as u32).sum::<u32>() / 3;
+	    let [y, u, v] = bgr_to_yuv(&bgr).map(|c| c as u32);
+	    match color {
+		[255, 255, 255] => {
+		    white = (white.0 + gray, white.1 + 1);
+		    white_luma = (white_luma.0 + y, white_luma.1 + 1);
+		},
+		[0, 0, 0] => {
+		    black = (black.0 + gray, black.1 + 1);
+		    black_luma = (black_luma.0 + y, black_luma.1 + 1);
+		},
+		_ => continue,
+	    }
+	    chroma = ([chroma.0[0] + u, chroma.0[1] + v], chroma.1 + 1);
+	}
+
+	let neutral = |sum: u32| sum.checked_div(chroma.1).map_or(128, |c| c as u8);
+
+	Self {
+	    bgr: [0, 1, 2].map(|c| midpoint(on[c], off[c])),
+	    gray: midpoint(white, black),
+	    yuv: [midpoint(white_luma, black_luma), neutral(chroma.0[0]), neutral(chroma.0[1])],
+	    ranges: [0, 1, 2].map(|c| range(off[c], on[c])),
+	    gray_range: range(black, white),
+	}
+    }
+}
+
+/// the closest of `levels` evenly spaced levels spanning `range` to `value`
+pub fn level(value: u8, (low, high): (u8, u8), levels: u32) -> u32 {
+    let step = (high - low) as f64 / (levels - 1) as f64;
+    let level = ((value as f64 - low as f64) / step).round();
+
+    level.clamp(0.0, (levels - 1) as f64) as u32
+}
+
+/// how far `value`, read as the level `found` among `levels` evenly spaced levels spanning
+/// `range`, is from the closest level whose symbol, given by `symbol`, differs on `bit`
+pub fn bit_distance(value: u8, (low, high): (u8, u8), levels: u32, found: u32, bit: usize, symbol: impl Fn(u32) -> u32) -> u8 {
+    let step = (high - low) as f64 / (levels - 1) as f64;
+    let value = value as f64 - low as f64;
+
+    (0..levels)
+	.filter(|other| (symbol(*other) ^ symbol(found)) >> bit & 1 == 1)
+	.map(|other| {
+	    // the boundary of the other level on the side of the found one
+	    let boundary = if other > found { other as f64 - 0.5 } else { other as f64 + 0.5 };
+	    (boundary * step - value).abs()
+	})
+	.fold(255.0, f64::min) as u8
+}
+
+/// means of the off and on levels, the full range when the patches are missing or unreadable
+fn range((off, off_count): (u32, u32), (on, on_count): (u32, u32)) -> (u8, u8) {
+    if on_count == 0 || off_count == 0 {
+	return (0, 255);
+    }
+
+    let (on, off) = (on / on_count, off / off_count);
+    if on <= off {
+	return (0, 255);
+    }
+
+    (off as u8, on as u8)
+}
+
+/// halfway between the means of the on and off levels, the middle of the range
+/// when the patches are missing or unreadable
+fn midpoint((on, on_count): (u32, u32), (off, off_count): (u32, u32)) -> u8 {
+    if on_count == 0 || off_count == 0 {
+	return 128;
+    }
+
+    let (on, off) = (on / on_count, off / off_count);
+    if on <= off {
+	return 128;
+    }
+
+    ((on + off) / 2) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an 8x8 block of `color` whose edges bled to black, with a bright outlier inside
+    fn damaged_block(color: u8) -> Image {
+	let mut frame = Image::new(8, 8);
+	for y in 1..7 {
+	    for x in 1..7 {
+		let i = ((y * 8 + x) * 3) as usize;
+		frame.data[i..i + 3].fill(color);
+	    }
+	}
+	frame.data[(3 * 8 + 4) * 3..(3 * 8 + 5) * 3].fill(255);
+	frame
+    }
+
+    #[test]
+    fn edges_and_outliers_are_left_out() {
+	assert_eq!(Sampler::new().block(&damaged_block(90), &Homography::scale(1.0, 1.0), Rect { x0: 0, y0: 0, x1: 8, y1: 8 }), [90; 3]);
+    }
+
+    #[test]
+    fn the_extremes_are_trimmed_before_averaging() {
+	assert_eq!(trimmed_mean(&mut [0, 10, 10, 20, 20, 255, 30, 30]), 20);
+    }
+
+    #[test]
+    fn the_threshold_follows_a_shifted_range() {
+	assert_eq!(midpoint((180 * 4, 4), (60 * 2, 2)), 120);
+    }
+
+    #[test]
+    fn unreadable_patches_fall_back_to_the_middle() {
+	assert_eq!(midpoint((0, 0), (60, 1)), 128);
+	assert_eq!(midpoint((50, 1), (200, 1)), 128);
+    }
+
+    #[test]
+    fn values_go_to_the_closest_level() {
+	assert_eq!(level(0, (0, 255), 8), 0);
+	assert_eq!(level(255, (0, 255), 8), 7);
+	assert_eq!(level(110, (0, 255), 8), 3);
+	assert_eq!(level(145, (0, 255), 8), 4);
+	assert_eq!(level(128, (0, 255), 2), 1);
+    }
+
+    #[test]
+    fn levels_follow_the_measured_range() {
+	assert_eq!(level(60, (20, 220), 3), 0);
+	assert_eq!(level(130, (20, 220), 3), 1);
+	assert_eq!(level(5, (20, 220), 3), 0);
+	assert_eq!(level(250, (20, 220), 3), 2);
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// xors `data`, the bytes of the frame at `index`, with a pseudo-random stream drawn from
+/// `seed`: whatever the data, zero padding and sparse files included, the frames look like
+/// noise and keep as many light blocks as dark ones. Scrambling twice gives the data back
+pub fn scramble(data: &mut [u8], seed: u32, index: usize) {
+    let mut state = (seed as u64) << 32 ^ index as u64;
+
+    for chunk in data.chunks_mut(8) {
+	for (b, k) in chunk.iter_mut().zip(splitmix64(&mut state).to_le_bytes()) {
+	    *b ^= k;
+	}
+    }
+}
+
+/// next number of the splitmix64 generator
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// a seed that changes on every call, from the random keys of the standard library
+pub fn random_seed() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrambling_twice_gives_the_data_back() {
+	let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+
+	let mut scrambled = data.clone();
+	scramble(&mut scrambled, 42, 3);
+	assert_ne!(scrambled, data);
+
+	scramble(&mut scrambled, 42, 3);
+	assert_eq!(scrambled, data);
+    }
+
+    #[test]
+    fn the_stream_depends_on_the_seed_and_the_frame() {
+	let stream = |seed, index| {
+	    let mut data = vec![0; 64];
+	    scramble(&mut data, seed, index);
+	    data
+	};
+
+	assert_ne!(stream(1, 0), stream(2, 0));
+	assert_ne!(stream(1, 0), stream(1, 1));
+    }
+
+    #[test]
+    fn zeros_come_out_as_noise() {
+	let mut data = vec![0u8; 4096];
+	scramble(&mut data, 7, 0);
+
+	let ones: u32 = data.iter().map(|b| b.count_ones()).sum();
+	let bits = data.len() as u32 * 8;
+	assert!(ones.abs_diff(bits / 2) < bits / 20);
+    }
+}